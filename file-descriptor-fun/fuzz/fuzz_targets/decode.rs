@@ -0,0 +1,31 @@
+//! Feeds made-up ring messages to `wire::decode`.
+//!
+//! The first byte picks how many FDs came with the message, the
+//! second one the `recvmsg` flags, and the rest is the payload. The
+//! FDs are just numbers (`decode` never touches them); every one of
+//! them has to come back out, either in the entry or in the error.
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate filedes;
+extern crate nix;
+
+use filedes::wire;
+use nix::sys::socket::MsgFlags;
+use std::os::unix::io::RawFd;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    let fds: Vec<RawFd> = (0..data[0] as RawFd % 20).map(|fd| fd + 100).collect();
+    let flags = MsgFlags::from_bits_truncate(data[1] as i32);
+    let payload = &data[2..];
+
+    let mut got = match wire::decode(payload, fds.clone(), flags) {
+        Ok(entry) => entry.fds(),
+        Err(malformed) => malformed.fds,
+    };
+    got.sort();
+    assert_eq!(fds, got);
+});
@@ -0,0 +1,358 @@
+//! Finding the ring configuration that hides the most FDs.
+//!
+//! How many FDs we can stash depends on the send buffer size, how
+//! many FDs go in each message, the socket type, and how many rings
+//! get stashed in each outer ring. A [`Sweep`](struct.Sweep.html)
+//! tries every combination of the values you give it, each in its own
+//! [`sandbox`](../sandbox/index.html), and reports which configuration
+//! stashed the most FDs per FD table slot it needed, and per byte of
+//! kernel memory (as far as `SIOCOUTQ` can tell, so Linux only).
+//!
+//! ```no_run
+//! use filedes::autotune;
+//!
+//! let results = autotune::Sweep::default().run().unwrap();
+//! println!("{}", results);
+//! ```
+
+use nix;
+use nix::sys::socket::SockType;
+use std::fmt;
+use std::os::unix::io::RawFd;
+
+use ring;
+use ring::{Ring, StashableThing};
+use sandbox;
+use sandbox::{Limits, Outcome, Report};
+use super::throwaway_file;
+
+/// One combination of parameters to try.
+#[derive(Copy, PartialEq, Eq, Clone, Debug)]
+pub struct Config {
+    /// The send buffer size to ask for, in bytes
+    pub send_buf_size: usize,
+
+    /// How many FDs to send in each message (up to
+    /// [`MAX_FDS_PER_ENTRY`](../ring/constant.MAX_FDS_PER_ENTRY.html))
+    pub fds_per_message: usize,
+
+    /// The type of the rings' socket pairs
+    pub socket_type: SockType,
+
+    /// How many full rings to stash in each outer ring; 0 means rings
+    /// aren't nested at all
+    pub fan_out: u64,
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} rings, {} KiB buffers, {} FDs/message, fan-out {}",
+               self.socket_type, self.send_buf_size / 1024, self.fds_per_message, self.fan_out)
+    }
+}
+
+/// The result of trying one [`Config`](struct.Config.html).
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Trial {
+    /// The configuration that was tried
+    pub config: Config,
+
+    /// The send buffer size the kernel actually granted (see
+    /// [`Ring::send_buffer_size`](../ring/struct.Ring.html#method.send_buffer_size))
+    pub send_buffer: u64,
+
+    /// How many FDs ended up stashed
+    pub stashed: u64,
+
+    /// How many FD table slots the rings holding them took up
+    pub slots: u64,
+
+    /// How many bytes of socket buffers the stashed FDs took up
+    pub kernel_bytes: u64,
+
+    /// What went wrong, if the trial didn't run to the end
+    pub error: Option<String>,
+}
+
+impl Trial {
+    /// Stashed FDs per FD table slot used.
+    pub fn per_slot(&self) -> f64 {
+        if self.slots == 0 { 0.0 } else { self.stashed as f64 / self.slots as f64 }
+    }
+
+    /// Stashed FDs per KiB of socket buffer used.
+    pub fn per_kib(&self) -> f64 {
+        if self.kernel_bytes == 0 { 0.0 } else { self.stashed as f64 * 1024.0 / self.kernel_bytes as f64 }
+    }
+}
+
+impl fmt::Display for Trial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (granted {} KiB): {} FDs in {} slots ({:.1}/slot), {} bytes ({:.2}/KiB)",
+                    self.config, self.send_buffer / 1024, self.stashed, self.slots, self.per_slot(),
+                    self.kernel_bytes, self.per_kib())?;
+        match self.error {
+            Some(ref e) => write!(f, " - {}", e),
+            None => Ok(()),
+        }
+    }
+}
+
+// The state of one trial run, in the sandboxed child:
+struct Run {
+    config: Config,
+    max_stashed: u64,
+    stashed: u64,
+    kernel_bytes: u64,
+    // Rings we hold on to: either all the rings (no nesting), or the
+    // outer rings.
+    held: Vec<Ring>,
+}
+
+impl Run {
+    // Fills a ring with groups of throwaway files until it's full,
+    // returning false if some other limit stopped it.
+    fn fill(&mut self, ring: &mut Ring, report: &mut Report) -> ring::Result<bool> {
+        let mut fds: Vec<RawFd> = vec![];
+        while self.stashed < self.max_stashed {
+            fds.clear();
+            let mut made = Ok(());
+            for _ in 0..self.config.fds_per_message {
+                match throwaway_file() {
+                    Ok(fd) => fds.push(fd),
+                    Err(e) => { made = Err(e); break; }
+                }
+            }
+            let added = made.and_then(|_| ring.add(StashableThing::Group(&fds)));
+            let closed = ring::close_all(&fds);
+            // An error from adding is more interesting than one from
+            // closing:
+            match added {
+                Ok(()) => {
+                    closed?;
+                    self.stashed += fds.len() as u64;
+                }
+                Err(ring::Error::Limit(nix::Error::Sys(nix::Errno::EAGAIN))) if ring.count > 0 => {
+                    closed?;
+                    return Ok(true);
+                }
+                Err(ring::Error::Limit(e)) => {
+                    closed?;
+                    report.set_errno(&e);
+                    return Ok(false);
+                }
+                Err(e) => { return Err(e); }
+            }
+        }
+        Ok(false)
+    }
+
+    // Stashes a full inner ring in the current outer ring, starting a
+    // new outer ring if that one's full. Returns false if some limit
+    // stopped it (the inner ring's FDs are lost then).
+    fn stash(&mut self, inner: Ring, report: &mut Report) -> ring::Result<bool> {
+        let bytes = inner.bytes_queued().unwrap_or(0) as u64;
+        let needs_outer = match self.held.last() {
+            Some(outer) => outer.count >= self.config.fan_out,
+            None => true,
+        };
+        if needs_outer {
+            self.held.push(ring::with_options(self.config.socket_type, self.config.send_buf_size)?);
+        }
+        let result = {
+            let outer = self.held.last_mut().unwrap();
+            match outer.add(&inner) {
+                Err(ring::Error::Limit(nix::Error::Sys(nix::Errno::EAGAIN))) if outer.count > 0 => None,
+                other => Some(other),
+            }
+        };
+        let result = match result {
+            Some(result) => result,
+            None => {
+                // The outer ring is full before reaching its fan-out:
+                self.held.push(ring::with_options(self.config.socket_type, self.config.send_buf_size)?);
+                self.held.last_mut().unwrap().add(&inner)
+            }
+        };
+        match result {
+            Ok(()) => {
+                self.kernel_bytes += bytes;
+                Ok(true)
+            }
+            Err(ring::Error::Limit(e)) => {
+                self.stashed -= inner.count * self.config.fds_per_message as u64;
+                report.set_errno(&e);
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Runs a single trial of `config` in the current process (stopping
+/// after `max_stashed` FDs), recording `"send_buffer"`, `"stashed"`,
+/// `"slots"` and `"kernel_bytes"` in `report`. This is what
+/// [`Sweep::run`](struct.Sweep.html#method.run) runs in its sandboxes.
+pub fn run_trial(config: Config, max_stashed: u64, report: &mut Report) -> ring::Result<()> {
+    let mut run = Run {
+        config: config,
+        max_stashed: max_stashed,
+        stashed: 0,
+        kernel_bytes: 0,
+        held: vec![],
+    };
+    loop {
+        let mut inner = ring::with_options(config.socket_type, config.send_buf_size)?;
+        if report.get("send_buffer").is_none() {
+            report.set("send_buffer", inner.send_buffer_size()? as u64);
+        }
+        let full = run.fill(&mut inner, report)?;
+        if inner.count == 0 {
+            break;
+        }
+        if config.fan_out == 0 {
+            run.held.push(inner);
+        } else if !run.stash(inner, report)? {
+            break;
+        }
+        if !full {
+            break;
+        }
+    }
+    for ring in run.held.iter() {
+        run.kernel_bytes += ring.bytes_queued().unwrap_or(0) as u64;
+    }
+    report.set("stashed", run.stashed);
+    report.set("slots", 2 * run.held.len() as u64);
+    report.set("kernel_bytes", run.kernel_bytes);
+    Ok(())
+}
+
+/// The parameter values to try; every combination gets a trial.
+#[derive(Clone, Debug)]
+pub struct Sweep {
+    pub send_buf_sizes: Vec<usize>,
+    pub fds_per_message: Vec<usize>,
+    pub socket_types: Vec<SockType>,
+    pub fan_outs: Vec<u64>,
+
+    /// Stop each trial after stashing this many FDs
+    pub max_stashed: u64,
+
+    /// The limits to run each trial with
+    pub limits: Limits,
+}
+
+impl Default for Sweep {
+    fn default() -> Sweep {
+        Sweep {
+            send_buf_sizes: vec![64 * 1024, 256 * 1024, 900 * 1024],
+            fds_per_message: vec![1, 4, ring::MAX_FDS_PER_ENTRY],
+            socket_types: vec![SockType::Stream, SockType::Datagram, SockType::SeqPacket],
+            fan_outs: vec![0, 16, 256],
+            max_stashed: 20000,
+            limits: Limits::nofile(1024),
+        }
+    }
+}
+
+impl Sweep {
+    /// Returns all the combinations of parameters.
+    pub fn configs(&self) -> Vec<Config> {
+        let mut configs = vec![];
+        for &socket_type in self.socket_types.iter() {
+            for &send_buf_size in self.send_buf_sizes.iter() {
+                for &fds_per_message in self.fds_per_message.iter() {
+                    for &fan_out in self.fan_outs.iter() {
+                        configs.push(Config {
+                            send_buf_size: send_buf_size,
+                            fds_per_message: fds_per_message,
+                            socket_type: socket_type,
+                            fan_out: fan_out,
+                        });
+                    }
+                }
+            }
+        }
+        configs
+    }
+
+    /// Runs a sandboxed trial for each combination of parameters.
+    pub fn run(&self) -> ring::Result<Results> {
+        let mut trials = vec![];
+        for config in self.configs() {
+            let max_stashed = self.max_stashed;
+            let outcome = sandbox::run(&self.limits, |report| run_trial(config, max_stashed, report))?;
+            let trial = match outcome {
+                Outcome::Completed(report) => {
+                    Trial {
+                        config: config,
+                        send_buffer: report.get("send_buffer").unwrap_or(0),
+                        stashed: report.get("stashed").unwrap_or(0),
+                        slots: report.get("slots").unwrap_or(0),
+                        kernel_bytes: report.get("kernel_bytes").unwrap_or(0),
+                        error: report.error.clone(),
+                    }
+                }
+                Outcome::Died(status) => {
+                    Trial {
+                        config: config,
+                        send_buffer: 0,
+                        stashed: 0,
+                        slots: 0,
+                        kernel_bytes: 0,
+                        error: Some(format!("died: {:?}", status)),
+                    }
+                }
+            };
+            trials.push(trial);
+        }
+        Ok(Results { trials: trials })
+    }
+}
+
+/// The results of a [`Sweep`](struct.Sweep.html).
+#[derive(Clone, Debug)]
+pub struct Results {
+    /// One trial per configuration, in the order of
+    /// [`Sweep::configs`](struct.Sweep.html#method.configs)
+    pub trials: Vec<Trial>,
+}
+
+impl Results {
+    fn best_by<F: Fn(&Trial) -> f64>(&self, score: F) -> Option<&Trial> {
+        let mut best: Option<&Trial> = None;
+        for trial in self.trials.iter().filter(|t| t.error.is_none()) {
+            best = match best {
+                Some(b) if score(b) >= score(trial) => Some(b),
+                _ => Some(trial),
+            };
+        }
+        best
+    }
+
+    /// The trial that stashed the most FDs per FD table slot.
+    pub fn best_per_slot(&self) -> Option<&Trial> {
+        self.best_by(|t| t.per_slot())
+    }
+
+    /// The trial that stashed the most FDs per byte of socket buffer.
+    pub fn best_per_byte(&self) -> Option<&Trial> {
+        self.best_by(|t| t.per_kib())
+    }
+}
+
+impl fmt::Display for Results {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for trial in self.trials.iter() {
+            writeln!(f, "{}", trial)?;
+        }
+        if let Some(best) = self.best_per_slot() {
+            writeln!(f, "Most FDs per slot: {}", best.config)?;
+        }
+        if let Some(best) = self.best_per_byte() {
+            writeln!(f, "Most FDs per byte: {}", best.config)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+//! Making syscalls fail on purpose, so the error paths get tested.
+//!
+//! Running into `EMFILE`, `ENFILE` or `ETOOMANYREFS` for real means
+//! exhausting the machine, so most of our error handling would never
+//! run in tests. The ring code calls [`check`](fn.check.html) right
+//! before each `sendmsg`, `recvmsg`, `socketpair` and `memfd_create`,
+//! and before the `fstat`s that in-memory rings use to find queues;
+//! in unit tests, [`inject`](fn.inject.html) makes the Nth of those
+//! calls fail with an errno of your choosing instead. Outside of
+//! tests, `check` does nothing.
+//!
+//! Like the [`watchdog`](../watchdog/index.html), injected faults are
+//! per thread, so tests running in parallel don't see each other's.
+
+use nix;
+
+/// The syscalls that faults can be injected into.
+#[derive(Copy, PartialEq, Eq, Clone, Debug)]
+pub enum Syscall {
+    SendMsg,
+    RecvMsg,
+    SocketPair,
+    MemFdCreate,
+    Fstat,
+}
+
+#[cfg(test)]
+pub use self::injection::inject;
+
+#[cfg(test)]
+mod injection {
+    use nix;
+    use std::cell::RefCell;
+    use super::Syscall;
+
+    struct Fault {
+        call: Syscall,
+        // How many more calls succeed before this one fails:
+        remaining: u64,
+        errno: nix::Errno,
+    }
+
+    thread_local!(static FAULTS: RefCell<Vec<Fault>> = RefCell::new(vec![]));
+
+    /// Removes all faults injected on the current thread when dropped.
+    pub struct Injected {
+        _private: (),
+    }
+
+    impl Drop for Injected {
+        fn drop(&mut self) {
+            FAULTS.with(|faults| faults.borrow_mut().clear());
+        }
+    }
+
+    /// Makes the `nth` call (counting from 1) to `call` on the
+    /// current thread fail with `errno`. Faults stay injected until
+    /// they fire or the returned value is dropped.
+    pub fn inject(call: Syscall, nth: u64, errno: nix::Errno) -> Injected {
+        assert!(nth > 0, "calls are counted from 1");
+        FAULTS.with(|faults| {
+            faults.borrow_mut().push(Fault {
+                call: call,
+                remaining: nth - 1,
+                errno: errno,
+            });
+        });
+        Injected { _private: () }
+    }
+
+    pub fn check(call: Syscall) -> nix::Result<()> {
+        FAULTS.with(|faults| {
+            let mut faults = faults.borrow_mut();
+            let mut fired = None;
+            for (i, fault) in faults.iter_mut().enumerate().filter(|&(_, ref f)| f.call == call) {
+                if fault.remaining > 0 {
+                    fault.remaining -= 1;
+                } else if fired.is_none() {
+                    fired = Some(i);
+                }
+            }
+            match fired {
+                Some(i) => Err(nix::Error::Sys(faults.remove(i).errno)),
+                None => Ok(()),
+            }
+        })
+    }
+}
+
+/// Returns the error injected for this call to `call`, if any.
+#[cfg(test)]
+#[inline]
+pub fn check(call: Syscall) -> nix::Result<()> {
+    injection::check(call)
+}
+
+/// Returns the error injected for this call to `call`, if any (there
+/// never is outside of tests).
+#[cfg(not(test))]
+#[inline]
+pub fn check(_call: Syscall) -> nix::Result<()> {
+    Ok(())
+}
+
+// Unit tests follow:
+
+#[test]
+fn faults_fire_on_the_nth_call_only() {
+    let _injected = inject(Syscall::SendMsg, 3, nix::Errno::EMFILE);
+    assert_eq!(Ok(()), check(Syscall::RecvMsg));
+    assert_eq!(Ok(()), check(Syscall::SendMsg));
+    assert_eq!(Ok(()), check(Syscall::SendMsg));
+    assert_eq!(Err(nix::Error::Sys(nix::Errno::EMFILE)), check(Syscall::SendMsg));
+    assert_eq!(Ok(()), check(Syscall::SendMsg));
+}
+
+#[test]
+fn faults_go_away_when_dropped() {
+    {
+        let _injected = inject(Syscall::SocketPair, 1, nix::Errno::ENFILE);
+    }
+    assert_eq!(Ok(()), check(Syscall::SocketPair));
+}
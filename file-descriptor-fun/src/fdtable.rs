@@ -0,0 +1,198 @@
+//! Looking at this process's file descriptor table without shelling
+//! out to `lsof`.
+//!
+//! [`entries`](fn.entries.html) lists every open FD (by enumerating
+//! `/proc/self/fd`), together with what it refers to and its flags &
+//! file position from `/proc/self/fdinfo`. This needs `/proc`, so it
+//! only works on Linux.
+
+use libc;
+use nix;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::os::unix::io::RawFd;
+
+use kind;
+use kind::{Description, FdKind};
+
+/// An entry in the process's file descriptor table.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct FdEntry {
+    /// The FD number
+    pub fd: RawFd,
+
+    /// What `fstat` says about the FD
+    pub kind: FdKind,
+
+    /// What the FD refers to, in more detail
+    pub description: Description,
+
+    /// Where `/proc/self/fd/<fd>` links to
+    pub target: PathBuf,
+
+    /// The file status flags (`O_RDWR`, `O_NONBLOCK`, ...)
+    pub flags: i32,
+
+    /// The current file offset
+    pub position: u64,
+}
+
+impl fmt::Display for FdEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>5}: {} (flags 0{:o}, pos {})",
+               self.fd, self.description, self.flags, self.position)
+    }
+}
+
+fn to_io_error(err: nix::Error) -> io::Error {
+    match err {
+        nix::Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+        nix::Error::InvalidPath => io::Error::new(io::ErrorKind::InvalidInput, "invalid path"),
+    }
+}
+
+/// Returns the table entry for `fd`, or `None` if `fd` isn't open
+/// (or gets closed by another thread while we look at it).
+pub fn entry(fd: RawFd) -> io::Result<Option<FdEntry>> {
+    match read_entry(fd) {
+        Ok(entry) => Ok(Some(entry)),
+        Err(ref e) if e.raw_os_error() == Some(libc::EBADF) => Ok(None),
+        // /proc/self/fd/<fd> is gone:
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_entry(fd: RawFd) -> io::Result<FdEntry> {
+    let kind = kind::kind_of(fd).map_err(to_io_error)?;
+    let description = kind::describe(fd).map_err(to_io_error)?;
+    let target = kind::fd_target(fd)?;
+    let info = kind::fdinfo(fd)?;
+    let flags = info.get("flags").and_then(|f| i32::from_str_radix(f, 8).ok()).unwrap_or(0);
+    let position = info.get("pos").and_then(|p| p.parse().ok()).unwrap_or(0);
+    Ok(FdEntry {
+        fd: fd,
+        kind: kind,
+        description: description,
+        target: target,
+        flags: flags,
+        position: position,
+    })
+}
+
+/// Returns all currently-open FDs of this process, ordered by FD
+/// number.
+///
+/// Note that other threads may open or close FDs while this runs,
+/// so the result is only exact in single-threaded situations.
+pub fn entries() -> io::Result<Vec<FdEntry>> {
+    // Collect the numbers first, so the FD that read_dir uses is
+    // closed by the time we look at the entries (and is skipped):
+    let mut fds: Vec<RawFd> = vec![];
+    for dirent in fs::read_dir("/proc/self/fd")? {
+        let dirent = dirent?;
+        if let Some(fd) = dirent.file_name().to_str().and_then(|name| name.parse().ok()) {
+            fds.push(fd);
+        }
+    }
+    fds.sort();
+
+    let mut entries = vec![];
+    for fd in fds {
+        if let Some(entry) = entry(fd)? {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// How the FD table changed between two calls to
+/// [`entries`](fn.entries.html).
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct Changes {
+    /// Entries that are open now but weren't before
+    pub opened: Vec<FdEntry>,
+
+    /// Entries that were open before but aren't now
+    pub closed: Vec<FdEntry>,
+}
+
+/// Compares two FD tables. An FD number that refers to something
+/// else in `after` than it did in `before` counts as closed and
+/// opened again; flags and file positions are ignored.
+pub fn changes(before: &[FdEntry], after: &[FdEntry]) -> Changes {
+    let same = |a: &FdEntry, b: &FdEntry| a.fd == b.fd && a.description == b.description;
+    Changes {
+        opened: after.iter().filter(|a| !before.iter().any(|b| same(a, b))).cloned().collect(),
+        closed: before.iter().filter(|b| !after.iter().any(|a| same(a, b))).cloned().collect(),
+    }
+}
+
+// Unit tests follow:
+
+#[cfg(target_os="linux")]
+#[test]
+fn it_lists_open_pipes() {
+    let _leaks = ::leaks::guard();
+    let (read, write) = nix::unistd::pipe().unwrap();
+    let table = entries().unwrap();
+    let read_entry = table.iter().find(|e| e.fd == read).unwrap();
+    let write_entry = table.iter().find(|e| e.fd == write).unwrap();
+    assert_eq!(FdKind::Fifo, read_entry.kind);
+    assert_eq!(read_entry.description, write_entry.description);
+    assert_eq!(0, read_entry.position);
+
+    nix::unistd::close(read).unwrap();
+    nix::unistd::close(write).unwrap();
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn it_finds_changes() {
+    let _leaks = ::leaks::guard();
+    let (read, write) = nix::unistd::pipe().unwrap();
+    let before = entries().unwrap();
+    nix::unistd::close(write).unwrap();
+    let (read2, write2) = nix::unistd::pipe().unwrap();
+    let after = entries().unwrap();
+
+    let changes = changes(&before, &after);
+    // `read2` most likely got `write`'s number, but it's a different pipe:
+    assert_eq!(vec![read2, write2], changes.opened.iter().map(|e| e.fd).collect::<Vec<_>>());
+    assert_eq!(vec![write], changes.closed.iter().map(|e| e.fd).collect::<Vec<_>>());
+
+    for fd in vec![read, read2, write2] {
+        nix::unistd::close(fd).unwrap();
+    }
+}
+
+// Other threads opening and closing FDs while we list them shouldn't
+// make listing fail (the leak guards rely on it).
+#[cfg(target_os="linux")]
+#[test]
+fn fds_closed_while_listing_are_skipped() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    let _leaks = ::leaks::guard();
+    let done = Arc::new(AtomicBool::new(false));
+    let churn = {
+        let done = done.clone();
+        thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                let (read, write) = nix::unistd::pipe().unwrap();
+                nix::unistd::close(read).unwrap();
+                nix::unistd::close(write).unwrap();
+            }
+        })
+    };
+    for _ in 0..1000 {
+        entries().unwrap();
+    }
+    done.store(true, Ordering::SeqCst);
+    churn.join().unwrap();
+    assert_eq!(None, entry(-1).unwrap());
+}
@@ -0,0 +1,185 @@
+//! Measuring how many FDs can be "in flight" in UNIX domain sockets.
+//!
+//! In 2016, an FD sitting in a socket buffer didn't count against
+//! anything, which is what made these experiments work. Since Linux
+//! 4.5, the kernel counts the FDs a *user* has in flight (across all
+//! their processes and sockets) against the sender's
+//! `RLIMIT_NOFILE`, and `sendmsg` fails with `ETOOMANYREFS` once
+//! there are more in flight than that (unless the sender has
+//! `CAP_SYS_RESOURCE` or `CAP_SYS_ADMIN`).
+//!
+//! [`measure`](fn.measure.html) finds out how this works on the
+//! current kernel: it stashes throwaway files across as many rings as
+//! it takes until a limit fires, and reports how many it got in
+//! flight, what the limits were, and which error stopped it.
+//! [`kernel_caps_in_flight`](fn.kernel_caps_in_flight.html) predicts
+//! the outcome without stashing anything.
+
+use nix;
+use std::fmt;
+use std::io;
+
+use limits::FdLimits;
+use ring;
+use super::add_tmpfile_to_ring;
+
+/// The results of an in-flight measurement.
+#[derive(Copy, PartialEq, Clone, Debug)]
+pub struct InFlight {
+    /// How many FDs were in flight when the measurement stopped
+    pub in_flight: u64,
+
+    /// How many rings the FDs were spread across
+    pub rings: u64,
+
+    /// The process's FD limits during the measurement
+    pub limits: FdLimits,
+
+    /// The error that stopped the measurement, or `None` if it ran up
+    /// to the maximum number of rings first
+    pub stopped_by: Option<nix::Error>,
+}
+
+impl InFlight {
+    /// Returns true if the kernel capped the in-flight FDs with
+    /// `ETOOMANYREFS` (that is, this kernel has in-flight accounting
+    /// and we're not privileged enough to get around it).
+    pub fn capped_by_kernel(&self) -> bool {
+        self.stopped_by == Some(nix::Error::Sys(nix::Errno::ETOOMANYREFS))
+    }
+
+    /// How many more FDs were in flight than the soft
+    /// `RLIMIT_NOFILE` allows to be open (negative if fewer).
+    pub fn beyond_soft_limit(&self) -> i64 {
+        self.in_flight as i64 - self.limits.soft as i64
+    }
+}
+
+impl fmt::Display for InFlight {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} FDs in flight across {} rings ({:+} vs. the soft limit; {}), ",
+                    self.in_flight, self.rings, self.beyond_soft_limit(), self.limits)?;
+        match self.stopped_by {
+            Some(e) => write!(f, "stopped by {}", e),
+            None => write!(f, "stopped at the maximum number of rings"),
+        }
+    }
+}
+
+/// Stashes throwaway files in up to `max_rings` rings (starting a new
+/// ring whenever one is full) until a limit is hit, and reports how
+/// far it got. All the rings are dropped before this returns.
+pub fn measure(max_rings: u64) -> ring::Result<InFlight> {
+    let limits = FdLimits::current()?;
+    let mut rings: Vec<ring::Ring> = vec![];
+    let mut in_flight = 0;
+    let mut stopped_by = None;
+
+    'rings: while (rings.len() as u64) < max_rings {
+        let mut current = match ring::new() {
+            Ok(ring) => ring,
+            Err(ring::Error::Limit(e)) => {
+                stopped_by = Some(e);
+                break;
+            }
+            Err(e) => { return Err(e); }
+        };
+        loop {
+            match add_tmpfile_to_ring(&mut current) {
+                Ok(n) => { in_flight += n; }
+                Err(ring::Error::Limit(nix::Error::Sys(nix::Errno::EAGAIN))) if current.count > 0 => {
+                    // Full, start a new one:
+                    rings.push(current);
+                    break;
+                }
+                Err(ring::Error::Limit(e)) => {
+                    stopped_by = Some(e);
+                    rings.push(current);
+                    break 'rings;
+                }
+                Err(e) => { return Err(e); }
+            }
+        }
+    }
+    Ok(InFlight {
+        in_flight: in_flight,
+        rings: rings.len() as u64,
+        limits: limits,
+        stopped_by: stopped_by,
+    })
+}
+
+/// Predicts whether the kernel caps the FDs this process can have in
+/// flight at its soft `RLIMIT_NOFILE` (that is, whether
+/// [`measure`](fn.measure.html) will end up
+/// [`capped_by_kernel`](struct.InFlight.html#method.capped_by_kernel)):
+/// true on Linux 4.5 or newer, unless the process has
+/// `CAP_SYS_RESOURCE` or `CAP_SYS_ADMIN` in the initial user
+/// namespace.
+#[cfg(target_os="linux")]
+pub fn kernel_caps_in_flight() -> io::Result<bool> {
+    use nix::sys::utsname;
+
+    if !release_at_least(utsname::uname().release(), (4, 5)) {
+        return Ok(false);
+    }
+    Ok(!privileged()?)
+}
+
+/// Predicts whether the kernel caps the FDs this process can have in
+/// flight. Only Linux is known to do that.
+#[cfg(not(target_os="linux"))]
+pub fn kernel_caps_in_flight() -> io::Result<bool> {
+    Ok(false)
+}
+
+// (internal) Whether a kernel release like "4.5.0-1-amd64" is at
+// least `wanted` (major, minor).
+#[cfg(target_os="linux")]
+fn release_at_least(release: &str, wanted: (u64, u64)) -> bool {
+    let mut numbers = release.split(|c: char| !c.is_digit(10)).map(|n| n.parse().unwrap_or(0));
+    let major = numbers.next().unwrap_or(0);
+    let minor = numbers.next().unwrap_or(0);
+    (major, minor) >= wanted
+}
+
+// (internal) Whether the kernel lets us past the in-flight limit:
+// capabilities only count in the initial user namespace (where
+// /proc/self/uid_map maps every UID to itself).
+#[cfg(target_os="linux")]
+fn privileged() -> io::Result<bool> {
+    use std::fs::File;
+    use std::io::Read;
+
+    const CAP_SYS_ADMIN: u64 = 21;
+    const CAP_SYS_RESOURCE: u64 = 24;
+
+    let mut uid_map = String::new();
+    File::open("/proc/self/uid_map")?.read_to_string(&mut uid_map)?;
+    if uid_map.split_whitespace().collect::<Vec<_>>() != ["0", "0", "4294967295"] {
+        return Ok(false);
+    }
+
+    let mut status = String::new();
+    File::open("/proc/self/status")?.read_to_string(&mut status)?;
+    let effective = status.lines()
+        .filter_map(|line| line.strip_prefix("CapEff:"))
+        .filter_map(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .next();
+    match effective {
+        Some(caps) => Ok(caps & (1 << CAP_SYS_ADMIN | 1 << CAP_SYS_RESOURCE) != 0),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "no CapEff in /proc/self/status")),
+    }
+}
+
+// Unit tests follow:
+
+#[cfg(target_os="linux")]
+#[test]
+fn kernel_releases_compare_by_major_and_minor() {
+    assert!(release_at_least("4.5.0-1-amd64", (4, 5)));
+    assert!(release_at_least("6.1.12", (4, 5)));
+    assert!(release_at_least("4.10", (4, 5)));
+    assert!(!release_at_least("4.4.0-21-generic", (4, 5)));
+    assert!(!release_at_least("3.19", (4, 5)));
+}
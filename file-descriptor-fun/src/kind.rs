@@ -0,0 +1,304 @@
+//! Figuring out what kind of thing a file descriptor refers to.
+//!
+//! Once an FD has been through a [`Ring`](../ring/struct.Ring.html),
+//! all you have is a number. The functions here ask the kernel what's
+//! behind that number (using `fstat` and, for sockets, `getsockopt` &
+//! `getsockname`).
+//!
+//! On Linux, [`describe`](fn.describe.html) goes further and looks at
+//! `/proc/self/fd` and `/proc/self/fdinfo` to tell you the memfd name,
+//! file path, pipe / socket inode etc.
+
+use libc;
+use nix;
+use nix::sys::stat;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::mem;
+use std::path::PathBuf;
+use std::os::unix::io::RawFd;
+
+/// The kind of socket an FD refers to.
+#[derive(Copy, PartialEq, Eq, Clone, Debug)]
+pub struct SocketKind {
+    /// The address family, e.g. `libc::AF_UNIX` or `libc::AF_INET`
+    pub domain: libc::c_int,
+
+    /// The socket type, e.g. `libc::SOCK_STREAM`
+    pub sock_type: libc::c_int,
+
+    /// Whether `listen` was called on the socket
+    pub listening: bool,
+}
+
+impl SocketKind {
+    /// Returns true if this is a UNIX domain socket.
+    pub fn is_unix(&self) -> bool {
+        self.domain == libc::AF_UNIX
+    }
+
+    /// Returns true if this is an IPv4 or IPv6 socket.
+    pub fn is_inet(&self) -> bool {
+        self.domain == libc::AF_INET || self.domain == libc::AF_INET6
+    }
+
+    /// Returns true if this is a stream socket.
+    pub fn is_stream(&self) -> bool {
+        self.sock_type == libc::SOCK_STREAM
+    }
+}
+
+/// The kind of thing an FD refers to, as reported by `fstat`.
+#[derive(Copy, PartialEq, Eq, Clone, Debug)]
+pub enum FdKind {
+    RegularFile,
+    Directory,
+    Fifo,
+    CharDevice,
+    BlockDevice,
+    Symlink,
+    Socket(SocketKind),
+
+    /// Something `fstat` doesn't have a file type for (e.g. an
+    /// eventfd on Linux)
+    Other,
+}
+
+/// Returns the kind of thing that `fd` refers to.
+pub fn kind_of(fd: RawFd) -> nix::Result<FdKind> {
+    let st = stat::fstat(fd)?;
+    let kind = match st.st_mode & libc::S_IFMT {
+        libc::S_IFREG => FdKind::RegularFile,
+        libc::S_IFDIR => FdKind::Directory,
+        libc::S_IFIFO => FdKind::Fifo,
+        libc::S_IFCHR => FdKind::CharDevice,
+        libc::S_IFBLK => FdKind::BlockDevice,
+        libc::S_IFLNK => FdKind::Symlink,
+        libc::S_IFSOCK => FdKind::Socket(socket_kind(fd)?),
+        _ => FdKind::Other,
+    };
+    Ok(kind)
+}
+
+fn socket_kind(fd: RawFd) -> nix::Result<SocketKind> {
+    Ok(SocketKind {
+        domain: socket_domain(fd)?,
+        sock_type: int_sockopt(fd, libc::SO_TYPE)?,
+        listening: int_sockopt(fd, libc::SO_ACCEPTCONN)? != 0,
+    })
+}
+
+fn int_sockopt(fd: RawFd, opt: libc::c_int) -> nix::Result<libc::c_int> {
+    let mut val: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, opt,
+                         &mut val as *mut libc::c_int as *mut libc::c_void,
+                         &mut len)
+    };
+    nix::Errno::result(res)?;
+    Ok(val)
+}
+
+// SO_DOMAIN only exists on some platforms, but every socket (even an
+// unnamed one from `socketpair`) has a family in its sockname:
+fn socket_domain(fd: RawFd) -> nix::Result<libc::c_int> {
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockname(fd,
+                          &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+                          &mut len)
+    };
+    nix::Errno::result(res)?;
+    Ok(addr.ss_family as libc::c_int)
+}
+
+/// A human-friendly description of what an FD refers to, as returned
+/// by [`describe`](fn.describe.html).
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Description {
+    /// A `memfd_create` file, with the name it was created with
+    MemFd(String),
+
+    /// A regular file, with its path (which may end in `" (deleted)"`
+    /// on Linux if the file was unlinked). The path is empty if it
+    /// can't be determined.
+    File(PathBuf),
+
+    /// A directory, with its path
+    Directory(PathBuf),
+
+    /// A character or block device, with its path
+    Device(PathBuf),
+
+    /// A pipe or FIFO, with its inode number
+    Pipe(u64),
+
+    /// A socket, with its kind and inode number
+    Socket(SocketKind, u64),
+
+    /// An eventfd, with its current counter value
+    EventFd(u64),
+
+    /// Some other `anon_inode` FD (timerfd, signalfd, epoll, inotify,
+    /// ...), with the kernel's name for it (e.g. `"[timerfd]"`).
+    AnonInode(String),
+
+    /// Something we couldn't describe any better than by its kind
+    Unknown(FdKind),
+}
+
+impl fmt::Display for Description {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Description::MemFd(ref name) => write!(f, "memfd {:?}", name),
+            Description::File(ref path) => write!(f, "file {}", path.display()),
+            Description::Directory(ref path) => write!(f, "directory {}", path.display()),
+            Description::Device(ref path) => write!(f, "device {}", path.display()),
+            Description::Pipe(ino) => write!(f, "pipe [{}]", ino),
+            Description::Socket(ref sock, ino) => {
+                let domain = match sock.domain {
+                    libc::AF_UNIX => "unix",
+                    libc::AF_INET => "inet",
+                    libc::AF_INET6 => "inet6",
+                    _ => "other",
+                };
+                let listening = if sock.listening { " (listening)" } else { "" };
+                write!(f, "{} socket [{}]{}", domain, ino, listening)
+            }
+            Description::EventFd(count) => write!(f, "eventfd (count {})", count),
+            Description::AnonInode(ref name) => write!(f, "anon_inode {}", name),
+            Description::Unknown(ref kind) => write!(f, "{:?}", kind),
+        }
+    }
+}
+
+/// Returns the path that `/proc/self/fd/<fd>` links to, e.g.
+/// `"socket:[1234]"` or `"/tmp/foo"`. Fails on systems without
+/// `/proc`.
+pub fn fd_target(fd: RawFd) -> io::Result<PathBuf> {
+    fs::read_link(format!("/proc/self/fd/{}", fd))
+}
+
+/// Returns the fields of `/proc/self/fdinfo/<fd>` (`pos`, `flags`,
+/// `mnt_id` and anything FD type-specific, like `eventfd-count`) as
+/// key / value pairs. Fails on systems without `/proc`.
+pub fn fdinfo(fd: RawFd) -> io::Result<HashMap<String, String>> {
+    let mut contents = String::new();
+    let mut file = fs::File::open(format!("/proc/self/fdinfo/{}", fd))?;
+    file.read_to_string(&mut contents)?;
+
+    let mut info = HashMap::new();
+    for line in contents.lines() {
+        if let Some(colon) = line.find(':') {
+            let (key, value) = line.split_at(colon);
+            info.insert(key.trim().to_owned(), value[1..].trim().to_owned());
+        }
+    }
+    Ok(info)
+}
+
+// Parses the inode number out of a link target like "pipe:[1234]".
+fn bracketed_inode(target: &str) -> Option<u64> {
+    let start = match target.find('[') { Some(i) => i + 1, None => return None };
+    let end = match target.rfind(']') { Some(i) => i, None => return None };
+    if start > end {
+        return None;
+    }
+    target[start..end].parse().ok()
+}
+
+/// Returns a description of what `fd` refers to. Uses `/proc` where
+/// available, and falls back to what `fstat` says otherwise.
+pub fn describe(fd: RawFd) -> nix::Result<Description> {
+    let kind = kind_of(fd)?;
+    let target = match fd_target(fd) {
+        Ok(path) => path,
+        Err(_) => {
+            // No /proc, so this is as good as it gets:
+            return Ok(match kind {
+                FdKind::RegularFile => Description::File(PathBuf::new()),
+                FdKind::Directory => Description::Directory(PathBuf::new()),
+                FdKind::Fifo => Description::Pipe(stat::fstat(fd)?.st_ino as u64),
+                FdKind::Socket(sock) => Description::Socket(sock, stat::fstat(fd)?.st_ino as u64),
+                other => Description::Unknown(other),
+            });
+        }
+    };
+    let target_str = target.to_string_lossy().into_owned();
+    let inode = bracketed_inode(&target_str);
+
+    let description = match kind {
+        FdKind::RegularFile if target_str.starts_with("/memfd:") => {
+            let name = target_str["/memfd:".len()..].trim_end_matches(" (deleted)");
+            Description::MemFd(name.to_owned())
+        }
+        FdKind::RegularFile => Description::File(target),
+        FdKind::Directory => Description::Directory(target),
+        FdKind::CharDevice | FdKind::BlockDevice => Description::Device(target),
+        FdKind::Fifo => Description::Pipe(inode.unwrap_or(0)),
+        FdKind::Socket(sock) => Description::Socket(sock, inode.unwrap_or(0)),
+        _ if target_str == "anon_inode:[eventfd]" => {
+            let count = fdinfo(fd).ok()
+                .and_then(|info| info.get("eventfd-count").and_then(|c| u64::from_str_radix(c, 16).ok()))
+                .unwrap_or(0);
+            Description::EventFd(count)
+        }
+        _ if target_str.starts_with("anon_inode:") => {
+            Description::AnonInode(target_str["anon_inode:".len()..].to_owned())
+        }
+        other => Description::Unknown(other),
+    };
+    Ok(description)
+}
+
+// Unit tests follow:
+
+#[test]
+fn it_recognizes_unix_sockets() {
+    let _leaks = ::leaks::guard();
+    let (one, two) = super::unix_socket_pair().unwrap();
+    match kind_of(one).unwrap() {
+        FdKind::Socket(sock) => {
+            assert!(sock.is_unix());
+            assert!(sock.is_stream());
+            assert!(!sock.listening);
+        }
+        other => { panic!("Expected a socket, got {:?}", other); }
+    }
+    nix::unistd::close(one).unwrap();
+    nix::unistd::close(two).unwrap();
+}
+
+#[test]
+fn it_recognizes_pipes() {
+    let _leaks = ::leaks::guard();
+    let (read, write) = nix::unistd::pipe().unwrap();
+    assert_eq!(FdKind::Fifo, kind_of(read).unwrap());
+    nix::unistd::close(read).unwrap();
+    nix::unistd::close(write).unwrap();
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn it_describes_memfds_and_pipes() {
+    use std::ffi::CString;
+
+    let _leaks = ::leaks::guard();
+    let name = CString::new("described").unwrap();
+    let fd = nix::sys::memfd::memfd_create(name.as_ref(), nix::sys::memfd::MemFdCreateFlag::empty()).unwrap();
+    assert_eq!(Description::MemFd("described".to_owned()), describe(fd).unwrap());
+    nix::unistd::close(fd).unwrap();
+
+    let (read, write) = nix::unistd::pipe().unwrap();
+    match describe(read).unwrap() {
+        Description::Pipe(ino) => { assert!(ino > 0); }
+        other => { panic!("Expected a pipe, got {}", other); }
+    }
+    nix::unistd::close(read).unwrap();
+    nix::unistd::close(write).unwrap();
+}
@@ -0,0 +1,195 @@
+//! Catching FDs that a test forgot to close.
+//!
+//! Most of this crate's code is about juggling FDs, and forgetting to
+//! close one on some error path goes unnoticed until a test runs out
+//! of FDs somewhere else. A [`Guard`](struct.Guard.html) takes a
+//! [`Snapshot`](struct.Snapshot.html) of the FD table when it's
+//! created, and panics when dropped if any FDs were opened since and
+//! not closed again, describing each of them:
+//!
+//! ```
+//! # extern crate filedes;
+//! # extern crate nix;
+//! use filedes::leaks;
+//!
+//! # fn main() {
+//! let _leaks = leaks::guard();
+//! let (read, write) = filedes::unix_socket_pair().unwrap();
+//! // ...
+//! # nix::unistd::close(read).unwrap();
+//! # nix::unistd::close(write).unwrap();
+//! # }
+//! ```
+//!
+//! The FD table belongs to the whole process, so guards on different
+//! threads take turns: only one guard is alive at a time, and creating
+//! another one waits until it's dropped. That means nested guards
+//! deadlock: creating a guard on a thread that already holds one
+//! waits forever. FDs opened by threads that don't hold a guard look
+//! like leaks, so every test in a test binary that opens FDs should
+//! use one.
+//!
+//! This reads `/proc/self/fd` (see [`fdtable`](../fdtable/index.html)),
+//! so it only checks anything on Linux; elsewhere, guards do nothing.
+//! On Linux, a guard that can't read the FD table panics rather than
+//! letting leaks go unnoticed.
+
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use fdtable;
+use fdtable::FdEntry;
+
+/// The FDs that were open at some point, and what they referred to.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Snapshot {
+    entries: Vec<FdEntry>,
+}
+
+impl Snapshot {
+    /// Records the FDs that are open right now.
+    pub fn take() -> io::Result<Snapshot> {
+        Ok(Snapshot { entries: fdtable::entries()? })
+    }
+
+    /// Returns the FDs that are open now but weren't when the
+    /// snapshot was taken. An FD number that was closed and reused
+    /// for something else counts as leaked.
+    pub fn leaked(&self) -> io::Result<Leaks> {
+        let entries = fdtable::entries()?;
+        Ok(Leaks(fdtable::changes(&self.entries, &entries).opened))
+    }
+}
+
+/// FDs that were left open, as found by
+/// [`Snapshot::leaked`](struct.Snapshot.html#method.leaked).
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Leaks(pub Vec<FdEntry>);
+
+impl Leaks {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for Leaks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} FDs leaked:", self.0.len())?;
+        for entry in self.0.iter() {
+            write!(f, "\n  {} [{:?} -> {}]", entry, entry.kind, entry.target.display())?;
+        }
+        Ok(())
+    }
+}
+
+// Whether some thread holds a guard:
+static GUARDED: AtomicBool = AtomicBool::new(false);
+
+/// Fails the current test (by panicking) if FDs were leaked while it
+/// was alive. See the [module docs](index.html).
+pub struct Guard {
+    // None where there's no FD table to look at:
+    before: Option<Snapshot>,
+}
+
+/// Waits until no other thread holds a guard, then starts watching
+/// for leaked FDs until the returned guard is dropped. Don't call
+/// this on a thread that already holds a guard: it would wait for
+/// itself forever.
+///
+/// Panics if the FD table can't be read.
+pub fn guard() -> Guard {
+    while GUARDED.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        thread::sleep(Duration::from_millis(1));
+    }
+    if !cfg!(target_os="linux") {
+        return Guard { before: None };
+    }
+    match Snapshot::take() {
+        Ok(before) => Guard { before: Some(before) },
+        Err(e) => {
+            GUARDED.store(false, Ordering::Release);
+            panic!("Can't check for leaked FDs, reading the FD table failed: {}", e);
+        }
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let leaks = self.before.as_ref().map(|before| before.leaked());
+        GUARDED.store(false, Ordering::Release);
+        // Panicking again would abort, and hide the original panic:
+        if thread::panicking() {
+            return;
+        }
+        match leaks {
+            Some(Ok(ref leaks)) if !leaks.is_empty() => { panic!("{}", leaks); }
+            Some(Err(e)) => { panic!("Can't check for leaked FDs, reading the FD table failed: {}", e); }
+            _ => {}
+        }
+    }
+}
+
+// Unit tests follow:
+
+#[cfg(target_os="linux")]
+#[test]
+fn it_finds_leaked_fds() {
+    use kind::FdKind;
+    use nix::unistd;
+
+    let _leaks = guard();
+    let before = Snapshot::take().unwrap();
+    let (read, write) = unistd::pipe().unwrap();
+    let leaks = before.leaked().unwrap();
+    assert_eq!(vec![read, write], leaks.0.iter().map(|e| e.fd).collect::<Vec<_>>());
+    assert_eq!(FdKind::Fifo, leaks.0[0].kind);
+    assert!(format!("{}", leaks).starts_with("2 FDs leaked:"));
+
+    unistd::close(read).unwrap();
+    unistd::close(write).unwrap();
+    assert!(before.leaked().unwrap().is_empty());
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn reused_fd_numbers_count_as_leaks() {
+    use nix::unistd;
+
+    let _leaks = guard();
+    let (read, write) = unistd::pipe().unwrap();
+    let before = Snapshot::take().unwrap();
+    let (read2, write2) = unistd::pipe().unwrap();
+    unistd::dup2(read2, read).unwrap();
+    let mut leaked: Vec<_> = before.leaked().unwrap().0.iter().map(|e| e.fd).collect();
+    leaked.sort();
+    assert_eq!(vec![read, read2, write2], leaked);
+
+    for fd in vec![read, write, read2, write2] {
+        unistd::close(fd).unwrap();
+    }
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn guards_panic_on_leaks() {
+    use nix::unistd;
+    use std::panic;
+
+    let mut leaked = vec![];
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        let _leaks = guard();
+        let (read, write) = unistd::pipe().unwrap();
+        leaked.push(read);
+        leaked.push(write);
+    }));
+    assert!(result.is_err());
+    for fd in leaked {
+        unistd::close(fd).unwrap();
+    }
+    // The panicking guard let go of the lock:
+    let _leaks = guard();
+}
@@ -1,5 +1,5 @@
 #![allow(dead_code)]
-#![deny(warnings)]
+#![cfg_attr(not(fuzzing), deny(warnings))]
 #![crate_type = "lib"]
 
 //! The top-level module filedes contains convenience / test stuff for playing with file descriptors.
@@ -11,15 +11,35 @@ extern crate nix;
 extern crate libc;
 
 pub mod ring;
+pub mod kind;
+pub mod fdtable;
+pub mod sysctl;
+pub mod monitor;
+pub mod limits;
+pub mod inflight;
+pub mod nesting;
+pub mod tree;
+pub mod watchdog;
+pub mod sandbox;
+pub mod scenario;
+pub mod autotune;
+pub mod source;
+pub mod transport;
+pub mod wire;
+pub mod leaks;
+mod faults;
 
 use nix::sys::socket;
 use nix::NixPath;
+use std::cell::Cell;
+use std::env;
 use std::ffi::{CString,OsStr};
 use std::path::{Path, PathBuf};
 use std::fs;
 use std::io;
 use std::os::unix::io::RawFd;
 use std::os::unix::ffi::OsStrExt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 const BASE_PATH: &'static str = "/tmp/filedes_fun/";
 const MAX_BACKLOG_QUEUE: usize = 265;
@@ -28,6 +48,9 @@ const SOCKET_TYPE: socket::SockType = socket::SockType::Stream;
 const SOCKET_PROTO: nix::c_int = 0;
 
 // Setup the directory they'll live in.
+//
+// All callers share this one directory, and `teardown` removes it
+// from under anyone else still using it; a `SocketDir` is better.
 pub fn setup() -> io::Result<()> {
     fs::create_dir_all(Path::new(self::BASE_PATH))
 }
@@ -40,127 +63,540 @@ fn sockpath(path: &str) -> PathBuf {
     Path::new(BASE_PATH).join(Path::new(path))
 }
 
+/// A uniquely named directory for UNIX domain sockets, removed
+/// (together with the sockets in it) when dropped.
+///
+/// Tests running in parallel can each have their own, so they don't
+/// trip over each other's sockets the way users of
+/// [`setup`](fn.setup.html) / [`teardown`](fn.teardown.html) do.
+#[derive(Debug)]
+pub struct SocketDir {
+    path: PathBuf,
+}
+
+// Tells apart the socket directories and abstract socket names of
+// one process:
+static UNIQUE_NAMES: AtomicUsize = AtomicUsize::new(0);
+
+// Returns a name that's different every time it's called in this
+// process, and (probably) from any other process's names:
+fn unique_name() -> String {
+    let n = UNIQUE_NAMES.fetch_add(1, Ordering::Relaxed);
+    format!("filedes_fun.{}.{}", nix::unistd::getpid(), n)
+}
+
+impl SocketDir {
+    /// Creates a new directory in the system's temp directory.
+    pub fn new() -> io::Result<SocketDir> {
+        loop {
+            let path = env::temp_dir().join(unique_name());
+            match fs::create_dir(&path) {
+                Ok(()) => { return Ok(SocketDir { path: path }); }
+                // Left over from an earlier process with our PID:
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => { return Err(e); }
+            }
+        }
+    }
+
+    /// The directory's path.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The path of the socket called `name` in this directory.
+    pub fn socket_path(&self, name: &str) -> PathBuf {
+        self.path.join(Path::new(name))
+    }
+
+    /// The address of the socket called `name` in this directory.
+    pub fn address(&self, name: &str) -> SocketAddress {
+        SocketAddress::Path(self.socket_path(name))
+    }
+}
+
+impl Drop for SocketDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Where a UNIX domain socket can be bound and connected to.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum SocketAddress {
+    /// A socket file at this path
+    Path(PathBuf),
+
+    /// A name in the abstract namespace (Linux only). These don't
+    /// show up in the filesystem, and the name is free again as soon
+    /// as the socket bound to it is closed.
+    Abstract(Vec<u8>),
+}
+
+impl SocketAddress {
+    /// Returns a new abstract address that no other socket uses.
+    pub fn unique_abstract() -> SocketAddress {
+        SocketAddress::Abstract(unique_name().into_bytes())
+    }
+
+    /// Converts the address for `bind` / `connect`.
+    pub fn to_sock_addr(&self) -> Result<socket::SockAddr, nix::Error> {
+        match *self {
+            SocketAddress::Path(ref path) => socket::SockAddr::new_unix(path.as_path()),
+            SocketAddress::Abstract(ref name) => abstract_addr(name),
+        }
+    }
+}
+
+#[cfg(target_os="linux")]
+fn abstract_addr(name: &[u8]) -> Result<socket::SockAddr, nix::Error> {
+    use std::mem;
+
+    // nix's UnixAddr::new_abstract leaves the leading NUL out of the
+    // address length (so the kernel sees the name without its last
+    // byte), and only checks the name's length without it:
+    let empty: libc::sockaddr_un = unsafe { mem::zeroed() };
+    if name.len() + 1 > empty.sun_path.len() {
+        return Err(nix::Error::Sys(nix::Errno::ENAMETOOLONG));
+    }
+    let socket::UnixAddr(addr, len) = socket::UnixAddr::new_abstract(name)?;
+    Ok(socket::SockAddr::Unix(socket::UnixAddr(addr, len + 1)))
+}
+
+#[cfg(not(target_os="linux"))]
+fn abstract_addr(_name: &[u8]) -> Result<socket::SockAddr, nix::Error> {
+    Err(nix::Error::Sys(nix::Errno::EOPNOTSUPP))
+}
+
 pub fn make_socket_addr(path: &str) -> Result<socket::SockAddr, nix::Error> {
     socket::SockAddr::new_unix(sockpath(path).as_path())
 }
 
-pub fn server_socket(path: &str) -> Result<RawFd, nix::Error> {
-    let socket = try!(socket::socket(socket::AddressFamily::Unix,
+/// Returns the address of the socket called `name` in `dir`.
+pub fn make_socket_addr_in(dir: &SocketDir, name: &str) -> Result<socket::SockAddr, nix::Error> {
+    dir.address(name).to_sock_addr()
+}
+
+// (internal) Creates a socket and sets it up with `f`, closing it if
+// that fails.
+fn unix_socket<F>(f: F) -> Result<RawFd, nix::Error>
+    where F: FnOnce(RawFd) -> Result<(), nix::Error>
+{
+    let socket = socket::socket(socket::AddressFamily::Unix,
                                      SOCKET_TYPE,
                                      socket::SockFlag::empty(),
-                                     SOCKET_PROTO));
-    let sockaddr = try!(make_socket_addr(path));
-    try!(socket::bind(socket, &sockaddr));
-    try!(socket::listen(socket, MAX_BACKLOG_QUEUE));
+                                     SOCKET_PROTO)?;
+    if let Err(e) = f(socket) {
+        let _ = nix::unistd::close(socket);
+        return Err(e);
+    }
     Ok(socket)
 }
 
+fn listen_on(sockaddr: &socket::SockAddr) -> Result<RawFd, nix::Error> {
+    unix_socket(|socket| {
+        socket::bind(socket, sockaddr)?;
+        socket::listen(socket, MAX_BACKLOG_QUEUE)
+    })
+}
+
+fn connect_to(sockaddr: &socket::SockAddr) -> Result<RawFd, nix::Error> {
+    unix_socket(|socket| socket::connect(socket, sockaddr))
+}
+
+/// Creates a socket called `path` in the shared directory (see
+/// [`setup`](fn.setup.html)) and listens on it.
+pub fn server_socket(path: &str) -> Result<RawFd, nix::Error> {
+    listen_on(&make_socket_addr(path)?)
+}
+
+/// Connects to the socket called `path` in the shared directory.
 pub fn connect_to_socket(path: &str) -> Result<RawFd, nix::Error> {
-    let socket = try!(socket::socket(socket::AddressFamily::Unix,
-                                     SOCKET_TYPE,
-                                     socket::SockFlag::empty(),
-                                     SOCKET_PROTO));
-    let sockaddr = try!(make_socket_addr(path));
-    try!(socket::connect(socket, &sockaddr));
-    Ok(socket)
+    connect_to(&make_socket_addr(path)?)
+}
+
+/// Creates a socket called `name` in `dir` and listens on it.
+pub fn server_socket_in(dir: &SocketDir, name: &str) -> Result<RawFd, nix::Error> {
+    server_socket_at(&dir.address(name))
+}
+
+/// Connects to the socket called `name` in `dir`.
+pub fn connect_to_socket_in(dir: &SocketDir, name: &str) -> Result<RawFd, nix::Error> {
+    connect_to_socket_at(&dir.address(name))
+}
+
+/// Creates a socket bound to `addr` and listens on it.
+///
+/// With an [`Abstract`](enum.SocketAddress.html#variant.Abstract)
+/// address, nothing is created in the filesystem, so there's nothing
+/// to clean up afterwards either.
+pub fn server_socket_at(addr: &SocketAddress) -> Result<RawFd, nix::Error> {
+    listen_on(&addr.to_sock_addr()?)
+}
+
+/// Connects to the socket bound to `addr`.
+pub fn connect_to_socket_at(addr: &SocketAddress) -> Result<RawFd, nix::Error> {
+    connect_to(&addr.to_sock_addr()?)
 }
 
 /// Creates a socketpair in the UNIX domain and returns it.
 pub fn unix_socket_pair() -> Result<(RawFd, RawFd), nix::Error> {
+    unix_socket_pair_of_type(SOCKET_TYPE)
+}
+
+/// Creates a non-blocking socketpair of the given type (stream,
+/// datagram or seqpacket) in the UNIX domain and returns it.
+pub fn unix_socket_pair_of_type(sock_type: socket::SockType) -> Result<(RawFd, RawFd), nix::Error> {
+    faults::check(faults::Syscall::SocketPair)?;
     return socket::socketpair(socket::AddressFamily::Unix,
-                              SOCKET_TYPE,
+                              sock_type,
                               SOCKET_PROTO,
                               socket::SOCK_NONBLOCK);
 }
 
-/// Creates a new pair of sockets with {unix_socket_pair} and adds it
-/// to the ring, and returns the number of sockets added.  This is the
-/// easiest way to get throwaway file descriptor outside wrapping
-/// `libc::mkstemp` (see [`add_tmpfile_to_ring`](fn.add_tmpfile_to_ring.html) for that) (:
+/// Creates a new pair of sockets with
+/// [`unix_socket_pair`](fn.unix_socket_pair.html), adds both of them
+/// to the ring as a single
+/// [`Group`](ring/enum.StashedThing.html#variant.Group) entry, and
+/// closes them. This is the easiest way to get throwaway file
+/// descriptor outside wrapping `libc::mkstemp` (see
+/// [`add_tmpfile_to_ring`](fn.add_tmpfile_to_ring.html) for that) (:
 ///
-/// This tries to close all file descriptors properly, but probably
-/// fails & leaks them at various points.
-pub fn add_two_sockets_to_ring(ring: &mut ring::Ring) -> ring::Result<u64> {
-    let (one, two) = try!(unix_socket_pair());
-    match ring.add(one) {
-        Ok(()) => {
-            try!(nix::unistd::close(one));
-        }
-        Err(ring::Error::Limit(e)) => {
-            println!("I hit {}", e);
-            try!(nix::unistd::close(one));
-            try!(nix::unistd::close(two));
-            return Err(ring::Error::Limit(e));
-        }
-        Err(e) => {
-            return Err(e);
-        }
-    }
-    match ring.add(two) {
-        Ok(()) => {
-            try!(nix::unistd::close(two));
-            Ok(2)
-        },
-        Err(ring::Error::Limit(e)) => {
-            println!("I hit {}", e);
-            try!(nix::unistd::close(two));
-            return Ok(1);
-        }
-        Err(e) => {
-            return Err(e);
-        }
-    }
+/// Either both sockets end up in the ring, or neither does; they get
+/// closed either way. On success, this returns
+/// [`Stored::Group(2)`](ring/enum.Stored.html).
+///
+/// If a [`watchdog`](watchdog/index.html) is armed and trips, this
+/// returns an error without creating any sockets.
+pub fn add_two_sockets_to_ring(ring: &mut ring::Ring) -> ring::Result<ring::Stored> {
+    watchdog::check()?;
+    let (one, two) = unix_socket_pair()?;
+    let stored = ring.add_together(&[one, two]);
+    let closed = nix::unistd::close(one).and(nix::unistd::close(two));
+    let stored = stored?;
+    closed?;
+    Ok(stored)
 }
 
 /// Returns the FD of an unlinked temporary file.
 #[inline]
 fn mkstemp<P: ?Sized + NixPath>(template: &P) -> ring::Result<(RawFd, PathBuf)> {
-    let (fd, pathname) = try!(template.with_nix_path(|path| {
+    let (fd, pathname) = template.with_nix_path(|path| {
         let owned_path = path.to_owned();
         let path_ptr = owned_path.into_raw();
         unsafe {
             (libc::mkstemp(path_ptr), CString::from_raw(path_ptr))
         }
-    }));
-    try!(nix::Errno::result(fd));
+    })?;
+    nix::Errno::result(fd)?;
     Ok((fd, Path::new(OsStr::from_bytes(pathname.as_bytes())).to_owned()))
 }
 
-#[cfg(not(target_os="linux"))]
-fn throwaway_file() -> ring::Result<RawFd> {
-    let (fd, name) = try!(mkstemp("/tmp/filedes_fun.XXXXXXXXXXXX"));
-    nix::unistd::unlink(name.as_path()).unwrap();
-    Ok(fd)
+/// Where [`add_tmpfile_to_ring`](fn.add_tmpfile_to_ring.html) & co.
+/// get their throwaway files from.
+#[derive(Copy, PartialEq, Eq, Clone, Debug)]
+pub enum Backend {
+    /// Anonymous files from `memfd_create`. Only Linux (since 3.17)
+    /// has those; elsewhere, this falls back to `TempFile`.
+    MemFd,
+
+    /// Temporary files from `mkstemp`, unlinked right away.
+    TempFile,
+}
+
+thread_local!(static BACKEND: Cell<Option<Backend>> = Cell::new(None));
+
+// Set once memfd_create turns out not to exist, so we don't keep
+// trying:
+static MEMFD_MISSING: AtomicBool = AtomicBool::new(false);
+
+/// Returns the backend to use if none was set: whatever the
+/// `FILEDES_THROWAWAY` environment variable says (`memfd` or
+/// `mkstemp`), otherwise `MemFd` on Linux and `TempFile` elsewhere.
+pub fn default_backend() -> Backend {
+    match env::var("FILEDES_THROWAWAY") {
+        Ok(ref name) if name == "mkstemp" => Backend::TempFile,
+        Ok(ref name) if name == "memfd" => Backend::MemFd,
+        _ if cfg!(target_os="linux") => Backend::MemFd,
+        _ => Backend::TempFile,
+    }
+}
+
+/// Sets the backend for throwaway files created on the current
+/// thread.
+pub fn set_backend(backend: Backend) {
+    BACKEND.with(|current| current.set(Some(backend)));
+}
+
+/// Returns the backend for throwaway files created on the current
+/// thread. If none was set, this is the
+/// [`default_backend`](fn.default_backend.html), which is only looked
+/// up the first time.
+pub fn backend() -> Backend {
+    BACKEND.with(|current| {
+        match current.get() {
+            Some(backend) => backend,
+            None => {
+                let backend = default_backend();
+                current.set(Some(backend));
+                backend
+            }
+        }
+    })
 }
 
 #[cfg(target_os="linux")]
-fn throwaway_file() -> ring::Result<RawFd> {
+fn memfd() -> ring::Result<RawFd> {
+    faults::check(faults::Syscall::MemFdCreate)?;
     let name = CString::new("foo").unwrap();
-    let fd = try!(nix::sys::memfd::memfd_create(name.as_ref(), nix::sys::memfd::MemFdCreateFlag::empty()));
+    Ok(nix::sys::memfd::memfd_create(name.as_ref(), nix::sys::memfd::MemFdCreateFlag::empty())?)
+}
+
+#[cfg(not(target_os="linux"))]
+fn memfd() -> ring::Result<RawFd> {
+    Err(ring::Error::Bad(nix::Error::Sys(nix::Errno::ENOSYS)))
+}
+
+fn tempfile() -> ring::Result<RawFd> {
+    let (fd, name) = mkstemp("/tmp/filedes_fun.XXXXXXXXXXXX")?;
+    if let Err(e) = nix::unistd::unlink(name.as_path()) {
+        nix::unistd::close(fd)?;
+        return Err(ring::Error::Bad(e));
+    }
     Ok(fd)
 }
 
-/// Uses `mkstemp` to create an open, unlinked temporary file and add
-/// its file descriptor to the [`Ring`](ring/struct.Ring.html)
-/// structure.
+/// (internal) Creates a file with `memfd`, falling back to `mkstemp`
+/// (for good) if it fails with `ENOSYS`.
+fn memfd_or_tempfile<F>(create_memfd: F, missing: &AtomicBool) -> ring::Result<RawFd>
+    where F: FnOnce() -> ring::Result<RawFd>
+{
+    if missing.load(Ordering::Relaxed) {
+        return tempfile();
+    }
+    match create_memfd() {
+        Err(ring::Error::Bad(nix::Error::Sys(nix::Errno::ENOSYS))) => {
+            missing.store(true, Ordering::Relaxed);
+            tempfile()
+        }
+        other => other,
+    }
+}
+
+fn throwaway_file() -> ring::Result<RawFd> {
+    match backend() {
+        Backend::MemFd => memfd_or_tempfile(memfd, &MEMFD_MISSING),
+        Backend::TempFile => tempfile(),
+    }
+}
+
+/// Creates a throwaway file (with the current thread's
+/// [`backend`](fn.backend.html): `memfd_create` or an unlinked
+/// `mkstemp` file) and adds its file descriptor to the
+/// [`Ring`](ring/struct.Ring.html) structure.
 ///
 /// This function closes the temporary file descriptor in any case
 /// (successs or error).
+///
+/// If a [`watchdog`](watchdog/index.html) is armed and trips, this
+/// returns an error without creating a file.
 pub fn add_tmpfile_to_ring(ring: &mut ring::Ring) -> ring::Result<u64> {
-    let fd = try!(throwaway_file());
+    watchdog::check()?;
+    let fd = throwaway_file()?;
     match ring.add(fd) {
         Ok(()) => {
-            try!(nix::unistd::close(fd));
+            nix::unistd::close(fd)?;
             Ok(1)
         }
         Err(ring::Error::Limit(e)) => {
-            try!(nix::unistd::close(fd));
+            nix::unistd::close(fd)?;
             return Err(ring::Error::Limit(e));
         }
         Err(e) => {
             println!("I don't understand what {:?} is", e);
-            try!(nix::unistd::close(fd));
+            nix::unistd::close(fd)?;
             return Err(e);
         }
     }
 }
+
+// Unit tests follow:
+
+#[test]
+fn memfd_falls_back_to_mkstemp_without_kernel_support() {
+    let _leaks = ::leaks::guard();
+    let missing = AtomicBool::new(false);
+    let enosys = || Err(ring::Error::Bad(nix::Error::Sys(nix::Errno::ENOSYS)));
+    let fd = memfd_or_tempfile(enosys, &missing).unwrap();
+    match kind::describe(fd).unwrap() {
+        kind::Description::File(_) => {}
+        other => { panic!("Expected a temp file, got {}", other); }
+    }
+    nix::unistd::close(fd).unwrap();
+    assert!(missing.load(Ordering::Relaxed));
+
+    // Once it's known to be missing, memfd_create isn't tried again:
+    let fd = memfd_or_tempfile(|| panic!("memfd_create was tried again"), &missing).unwrap();
+    nix::unistd::close(fd).unwrap();
+}
+
+#[test]
+fn socket_pairs_go_in_whole_or_not_at_all() {
+    let _leaks = ::leaks::guard();
+    let mut ring = ring::with_send_buffer(16 * 1024).unwrap();
+    loop {
+        match add_two_sockets_to_ring(&mut ring) {
+            Ok(stored) => { assert_eq!(ring::Stored::Group(2), stored); }
+            Err(ring::Error::Limit(_)) => { break; }
+            Err(e) => { panic!("Unexpected {:?}", e); }
+        }
+    }
+    assert!(ring.count > 0);
+    while ring.count > 0 {
+        match ring.pop().unwrap() {
+            ring::StashedThing::Group(fds) => {
+                assert_eq!(2, fds.len());
+                for fd in fds {
+                    nix::unistd::close(fd).unwrap();
+                }
+            }
+            _ => { panic!("Expected both sockets in one entry"); }
+        }
+    }
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn adding_a_tmpfile_passes_errors_on() {
+    use faults::{inject, Syscall};
+
+    let _leaks = ::leaks::guard();
+    set_backend(Backend::MemFd);
+    let mut ring = ring::new().unwrap();
+    {
+        let _injected = inject(Syscall::MemFdCreate, 1, nix::Errno::EMFILE);
+        match add_tmpfile_to_ring(&mut ring) {
+            Err(ring::Error::Limit(nix::Error::Sys(nix::Errno::EMFILE))) => {}
+            other => { panic!("Expected EMFILE, got {:?}", other); }
+        }
+    }
+    {
+        let _injected = inject(Syscall::SendMsg, 1, nix::Errno::EAGAIN);
+        match add_tmpfile_to_ring(&mut ring) {
+            Err(ring::Error::Limit(nix::Error::Sys(nix::Errno::EAGAIN))) => {}
+            other => { panic!("Expected EAGAIN, got {:?}", other); }
+        }
+    }
+    {
+        let _injected = inject(Syscall::SendMsg, 1, nix::Errno::EBADF);
+        match add_tmpfile_to_ring(&mut ring) {
+            Err(ring::Error::Bad(nix::Error::Sys(nix::Errno::EBADF))) => {}
+            other => { panic!("Expected EBADF, got {:?}", other); }
+        }
+    }
+    assert_eq!(0, ring.count);
+    assert_eq!(1, add_tmpfile_to_ring(&mut ring).unwrap());
+    assert_eq!(1, ring.count);
+}
+
+#[test]
+fn adding_two_sockets_passes_errors_on() {
+    use faults::{inject, Syscall};
+
+    let _leaks = ::leaks::guard();
+    let mut ring = ring::new().unwrap();
+    {
+        let _injected = inject(Syscall::SocketPair, 1, nix::Errno::ENFILE);
+        match add_two_sockets_to_ring(&mut ring) {
+            Err(ring::Error::Limit(nix::Error::Sys(nix::Errno::ENFILE))) => {}
+            other => { panic!("Expected ENFILE, got {:?}", other); }
+        }
+    }
+    {
+        let _injected = inject(Syscall::SendMsg, 1, nix::Errno::ETOOMANYREFS);
+        match add_two_sockets_to_ring(&mut ring) {
+            Err(ring::Error::Limit(nix::Error::Sys(nix::Errno::ETOOMANYREFS))) => {}
+            other => { panic!("Expected ETOOMANYREFS, got {:?}", other); }
+        }
+    }
+    {
+        let _injected = inject(Syscall::SendMsg, 1, nix::Errno::EINVAL);
+        match add_two_sockets_to_ring(&mut ring) {
+            Err(ring::Error::Bad(nix::Error::Sys(nix::Errno::EINVAL))) => {}
+            other => { panic!("Expected EINVAL, got {:?}", other); }
+        }
+    }
+    assert_eq!(0, ring.count);
+    assert_eq!(ring::Stored::Group(2), add_two_sockets_to_ring(&mut ring).unwrap());
+    assert_eq!(1, ring.count);
+}
+
+#[test]
+fn socket_dirs_are_separate_and_cleaned_up() {
+    let _leaks = ::leaks::guard();
+    let one = SocketDir::new().unwrap();
+    let two = SocketDir::new().unwrap();
+    assert!(one.path() != two.path());
+
+    let server = server_socket_in(&one, "sock").unwrap();
+    assert!(one.socket_path("sock").exists());
+    match connect_to_socket_in(&two, "sock") {
+        Err(nix::Error::Sys(nix::Errno::ENOENT)) => {}
+        other => { panic!("Expected no socket in the other dir, got {:?}", other); }
+    }
+    let client = connect_to_socket_in(&one, "sock").unwrap();
+    nix::unistd::close(client).unwrap();
+    nix::unistd::close(server).unwrap();
+
+    let path = one.path().to_owned();
+    drop(one);
+    assert!(!path.exists());
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn abstract_sockets_work_without_files() {
+    use std::io::Read;
+
+    let _leaks = ::leaks::guard();
+    let addr = SocketAddress::unique_abstract();
+    let name = match addr {
+        SocketAddress::Abstract(ref name) => String::from_utf8(name.clone()).unwrap(),
+        _ => unreachable!(),
+    };
+    let server = server_socket_at(&addr).unwrap();
+    let client = connect_to_socket_at(&addr).unwrap();
+    let conn = socket::accept(server).unwrap();
+
+    // The kernel sees the whole name (see abstract_addr):
+    let mut table = String::new();
+    fs::File::open("/proc/net/unix").unwrap().read_to_string(&mut table).unwrap();
+    assert!(table.lines().any(|line| line.ends_with(&format!(" @{}", name))),
+            "@{} isn't in /proc/net/unix", name);
+
+    for &fd in [conn, client, server].iter() {
+        nix::unistd::close(fd).unwrap();
+    }
+    // ...and the name is free again:
+    let server = server_socket_at(&addr).unwrap();
+    nix::unistd::close(server).unwrap();
+
+    let too_long = SocketAddress::Abstract(vec![b'x'; 108]);
+    assert_eq!(Err(nix::Error::Sys(nix::Errno::ENAMETOOLONG)), connect_to_socket_at(&too_long));
+}
+
+#[test]
+fn a_tripped_watchdog_stops_adding() {
+    let _leaks = ::leaks::guard();
+    let mut ring = ring::new().unwrap();
+    let _armed = watchdog::arm(watchdog::Watchdog::new(0, Some(std::time::Duration::from_secs(0))));
+    match add_tmpfile_to_ring(&mut ring) {
+        Err(ring::Error::Tripped(watchdog::Tripped::OutOfTime(_))) => {}
+        other => { panic!("Expected the watchdog to trip, got {:?}", other); }
+    }
+    match add_two_sockets_to_ring(&mut ring) {
+        Err(ring::Error::Tripped(watchdog::Tripped::OutOfTime(_))) => {}
+        other => { panic!("Expected the watchdog to trip, got {:?}", other); }
+    }
+    assert_eq!(0, ring.count);
+}
@@ -0,0 +1,132 @@
+//! Reading and changing the per-process FD limit (`RLIMIT_NOFILE`).
+//!
+//! Every experiment here is about getting past this limit somehow, so
+//! it helps to know exactly what it was:
+//! [`FdLimits::current`](struct.FdLimits.html#method.current) returns
+//! the soft and hard limit and `fs.nr_open` (the most the hard limit
+//! can be raised to), and
+//! [`exceeded_by`](struct.FdLimits.html#method.exceeded_by) tells you
+//! which of them some number of FDs went past.
+
+use libc;
+use nix;
+use std::fmt;
+use std::mem;
+
+use monitor;
+
+/// One of the limits on how many FDs a process can have.
+#[derive(Copy, PartialEq, Eq, Clone, Debug)]
+pub enum Limit {
+    /// The soft `RLIMIT_NOFILE`, which is what `open` & co. check
+    Soft,
+
+    /// The hard `RLIMIT_NOFILE`, which the soft limit can be raised to
+    Hard,
+
+    /// `fs.nr_open`, which the hard limit can be raised to
+    NrOpen,
+}
+
+/// The FD limits of this process.
+#[derive(Copy, PartialEq, Eq, Clone, Debug)]
+pub struct FdLimits {
+    /// The soft `RLIMIT_NOFILE`
+    pub soft: u64,
+
+    /// The hard `RLIMIT_NOFILE`
+    pub hard: u64,
+
+    /// `fs.nr_open`, if it could be read (it's Linux only)
+    pub nr_open: Option<u64>,
+}
+
+impl FdLimits {
+    /// Returns the current limits.
+    pub fn current() -> nix::Result<FdLimits> {
+        let (soft, hard) = nofile()?;
+        Ok(FdLimits {
+            soft: soft,
+            hard: hard,
+            nr_open: monitor::nr_open().ok(),
+        })
+    }
+
+    /// Returns the highest limit that `count` FDs went past, and by
+    /// how much, or `None` if `count` is within the soft limit.
+    pub fn exceeded_by(&self, count: u64) -> Option<(Limit, u64)> {
+        let limits = [(Limit::NrOpen, self.nr_open),
+                      (Limit::Hard, Some(self.hard)),
+                      (Limit::Soft, Some(self.soft))];
+        for &(limit, value) in limits.iter() {
+            if let Some(value) = value {
+                if count > value {
+                    return Some((limit, count - value));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Display for FdLimits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RLIMIT_NOFILE soft {}, hard {}", self.soft, self.hard)?;
+        match self.nr_open {
+            Some(nr_open) => write!(f, ", fs.nr_open {}", nr_open),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Returns the soft and hard `RLIMIT_NOFILE`.
+pub fn nofile() -> nix::Result<(u64, u64)> {
+    let mut limit: libc::rlimit = unsafe { mem::zeroed() };
+    let res = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+    nix::Errno::result(res)?;
+    Ok((limit.rlim_cur as u64, limit.rlim_max as u64))
+}
+
+/// Sets the soft and hard `RLIMIT_NOFILE`. Raising the hard limit
+/// needs privileges (and can't go past `fs.nr_open`).
+pub fn set_nofile(soft: u64, hard: u64) -> nix::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: soft as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    };
+    let res = unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) };
+    nix::Errno::result(res)?;
+    Ok(())
+}
+
+/// Sets the soft `RLIMIT_NOFILE`, leaving the hard limit alone.
+pub fn set_soft_nofile(soft: u64) -> nix::Result<()> {
+    let (_, hard) = nofile()?;
+    set_nofile(soft, hard)
+}
+
+/// Raises the soft `RLIMIT_NOFILE` as far as it goes (to the hard
+/// limit), and returns the new soft limit.
+pub fn raise_nofile() -> nix::Result<u64> {
+    let (_, hard) = nofile()?;
+    set_nofile(hard, hard)?;
+    Ok(hard)
+}
+
+// Unit tests follow:
+
+#[test]
+fn exceeded_by_names_the_highest_limit() {
+    let limits = FdLimits { soft: 10, hard: 20, nr_open: Some(30) };
+    assert_eq!(None, limits.exceeded_by(10));
+    assert_eq!(Some((Limit::Soft, 5)), limits.exceeded_by(15));
+    assert_eq!(Some((Limit::Hard, 1)), limits.exceeded_by(21));
+    assert_eq!(Some((Limit::NrOpen, 70)), limits.exceeded_by(100));
+}
+
+#[test]
+fn it_reads_the_current_limits() {
+    let _leaks = ::leaks::guard();
+    let limits = FdLimits::current().unwrap();
+    assert!(limits.soft <= limits.hard);
+}
@@ -0,0 +1,181 @@
+//! Watching the system-wide file table while an experiment runs.
+//!
+//! The interesting experiments here try to run the *kernel* out of
+//! file descriptions, not just the process. A
+//! [`Monitor`](struct.Monitor.html) samples `fs.file-nr` (how many
+//! file handles are allocated system-wide) as the experiment goes
+//! along, so you can see how close to `fs.file-max` it got.
+//!
+//! ```no_run
+//! use filedes::{monitor, ring, add_tmpfile_to_ring};
+//!
+//! let mut ring = ring::new().unwrap();
+//! let mut monitor = monitor::Monitor::new(100).unwrap();
+//! while add_tmpfile_to_ring(&mut ring).is_ok() {
+//!     monitor.step().unwrap();
+//! }
+//! println!("{}", monitor);
+//! ```
+
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+use sysctl;
+
+/// The state of the system-wide file table at one point in time.
+#[derive(Copy, PartialEq, Eq, Clone, Debug)]
+pub struct FileTable {
+    /// The number of allocated file handles
+    pub allocated: u64,
+
+    /// The number of allocated but unused file handles (always 0 on
+    /// Linux 2.6 and later)
+    pub unused: u64,
+
+    /// The maximum number of file handles (`fs.file-max`)
+    pub max: u64,
+}
+
+impl FileTable {
+    /// Reads the current state of the file table from `fs.file-nr`.
+    pub fn current() -> io::Result<FileTable> {
+        let values = sysctl::read("fs.file-nr")?;
+        if values.len() != 3 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("fs.file-nr has {} fields", values.len())));
+        }
+        Ok(FileTable {
+            allocated: values[0],
+            unused: values[1],
+            max: values[2],
+        })
+    }
+
+    /// The number of file handles that can still be allocated.
+    pub fn free(&self) -> u64 {
+        self.max.saturating_sub(self.allocated.saturating_sub(self.unused))
+    }
+}
+
+impl fmt::Display for FileTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} of {} file handles allocated ({} free)",
+               self.allocated, self.max, self.free())
+    }
+}
+
+/// Returns the maximum any process's `RLIMIT_NOFILE` can be set to
+/// (`fs.nr_open`).
+pub fn nr_open() -> io::Result<u64> {
+    sysctl::read_one("fs.nr_open")
+}
+
+/// A sample taken by a [`Monitor`](struct.Monitor.html).
+#[derive(Copy, PartialEq, Eq, Clone, Debug)]
+pub struct Sample {
+    /// The experiment step during which the sample was taken
+    pub step: u64,
+
+    /// The time since the monitor was created
+    pub elapsed: Duration,
+
+    /// The state of the file table
+    pub table: FileTable,
+}
+
+impl fmt::Display for Sample {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "step {:>8} @ {:>4}.{:03}s: {}",
+               self.step, self.elapsed.as_secs(), self.elapsed.subsec_nanos() / 1_000_000,
+               self.table)
+    }
+}
+
+/// Samples the system-wide file table over the course of an
+/// experiment.
+///
+/// Call [`step`](#method.step) once per step of the experiment (e.g.
+/// once per FD stashed); every `every` steps, the monitor takes a
+/// sample.
+pub struct Monitor {
+    start: Instant,
+    every: u64,
+    steps: u64,
+
+    /// `fs.nr_open` at the time the monitor was created
+    pub nr_open: u64,
+
+    /// All the samples taken so far, oldest first
+    pub samples: Vec<Sample>,
+}
+
+impl Monitor {
+    /// Creates a new monitor that samples every `every` steps, and
+    /// takes an initial sample.
+    pub fn new(every: u64) -> io::Result<Monitor> {
+        let mut monitor = Monitor {
+            start: Instant::now(),
+            every: if every == 0 { 1 } else { every },
+            steps: 0,
+            nr_open: nr_open()?,
+            samples: vec![],
+        };
+        monitor.sample()?;
+        Ok(monitor)
+    }
+
+    /// Records one step of the experiment, taking a sample if it's
+    /// time to.
+    pub fn step(&mut self) -> io::Result<()> {
+        self.steps += 1;
+        if self.steps % self.every == 0 {
+            self.sample()?;
+        }
+        Ok(())
+    }
+
+    /// Takes a sample right now and returns it.
+    pub fn sample(&mut self) -> io::Result<Sample> {
+        let sample = Sample {
+            step: self.steps,
+            elapsed: self.start.elapsed(),
+            table: FileTable::current()?,
+        };
+        self.samples.push(sample);
+        Ok(sample)
+    }
+
+    /// Returns the sample with the most allocated file handles.
+    pub fn peak(&self) -> Option<&Sample> {
+        self.samples.iter().max_by_key(|s| s.table.allocated)
+    }
+}
+
+impl fmt::Display for Monitor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "File table over {} steps (fs.nr_open = {}):", self.steps, self.nr_open)?;
+        for sample in self.samples.iter() {
+            writeln!(f, "  {}", sample)?;
+        }
+        match self.peak() {
+            Some(peak) => write!(f, "Peak: {}", peak),
+            None => Ok(()),
+        }
+    }
+}
+
+// Unit tests follow:
+
+#[cfg(target_os="linux")]
+#[test]
+fn monitor_samples_every_n_steps() {
+    let _leaks = ::leaks::guard();
+    let mut monitor = Monitor::new(2).unwrap();
+    for _ in 0..5 {
+        monitor.step().unwrap();
+    }
+    let steps: Vec<u64> = monitor.samples.iter().map(|s| s.step).collect();
+    assert_eq!(vec![0, 2, 4], steps);
+    assert!(monitor.peak().unwrap().table.max > 0);
+}
@@ -0,0 +1,87 @@
+//! Finding out how deeply rings can be nested in rings.
+//!
+//! Linux only lets you send a UNIX domain socket over another one if
+//! the socket you're sending doesn't already carry sockets that carry
+//! sockets that carry... more than a few levels deep (it fails with
+//! `ETOOMANYREFS` past that). [`probe`](fn.probe.html) finds the
+//! limit by stashing rings inside rings inside rings until the kernel
+//! says no.
+//!
+//! Newer kernels (with the reworked socket GC) don't seem to have
+//! this limit anymore, so give the probe a maximum depth.
+
+use nix;
+use std::fmt;
+
+use ring;
+use watchdog;
+use super::add_tmpfile_to_ring;
+
+/// The result of probing the nesting depth.
+#[derive(Copy, PartialEq, Clone, Debug)]
+pub struct Nesting {
+    /// How many rings deep the innermost ring could be stashed: 1
+    /// means a ring could go into a ring, but that couldn't go into
+    /// another ring.
+    pub depth: u64,
+
+    /// The error that stopped the probe, or `None` if it reached the
+    /// maximum depth it was asked to try first. This is usually
+    /// `ETOOMANYREFS`, but without a nesting limit, the probe may run
+    /// out of FDs for new rings (`EMFILE`) before it gets that deep.
+    pub stopped_by: Option<nix::Error>,
+}
+
+impl fmt::Display for Nesting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rings nest {} deep", self.depth)?;
+        match self.stopped_by {
+            Some(e) => write!(f, " (stopped by {})", e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Stashes a throwaway file in a ring, then stashes that ring in a
+/// new ring, and so on, until a limit is hit or the rings are
+/// `max_depth` deep. All the rings are dropped before this returns.
+///
+/// # Errors
+/// * [`Tripped`](../ring/enum.Error.html#variant.Tripped) - if the
+///   current thread's [`watchdog`](../watchdog/index.html) tripped
+///   before the probe was done.
+pub fn probe(max_depth: u64) -> ring::Result<Nesting> {
+    watchdog::check()?;
+    let mut innermost = ring::new()?;
+    add_tmpfile_to_ring(&mut innermost)?;
+
+    let mut current = innermost;
+    let mut depth = 0;
+    let mut stopped_by = None;
+    while depth < max_depth {
+        watchdog::check()?;
+        let mut outer = match ring::new() {
+            Ok(outer) => outer,
+            Err(ring::Error::Limit(e)) => {
+                stopped_by = Some(e);
+                break;
+            }
+            Err(e) => { return Err(e); }
+        };
+        match outer.add(&current) {
+            Ok(()) => {}
+            Err(ring::Error::Limit(e)) => {
+                stopped_by = Some(e);
+                break;
+            }
+            Err(e) => { return Err(e); }
+        }
+        // `current` lives on in `outer` now:
+        current = outer;
+        depth += 1;
+    }
+    Ok(Nesting {
+        depth: depth,
+        stopped_by: stopped_by,
+    })
+}
@@ -1,18 +1,61 @@
+use libc;
 use nix;
 use nix::sys::socket;
-use nix::sys::uio::IoVec;
 use nix::unistd;
 use std::result;
 use std::fmt;
 use std::num;
 use std::str;
-use std::str::FromStr;
+use std::fs::File;
+use std::net::TcpStream;
+use std::os::unix::net::{UnixStream, UnixListener};
 
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use kind;
+use kind::{Description, FdKind};
+use sysctl;
+use transport;
+use transport::Transport;
+use watchdog;
+use wire;
+use wire::Entry;
 
 // OS X doesn't let us go beyond 256kB for the buffer size, so this is the max:
 const SEND_BUF_SIZE: usize = 900 * 1024;
 
+/// The maximum number of FDs that a single entry (e.g. a
+/// [`Group`](enum.StashedThing.html#variant.Group)) can hold.
+pub const MAX_FDS_PER_ENTRY: usize = 15;
+
+// Calibration rings get a small buffer, so that filling them is quick
+// and stays far below any limit on FDs in flight:
+const CALIBRATION_BUF_SIZE: usize = 16 * 1024;
+
+/// What [`Ring::add_together`](struct.Ring.html#method.add_together)
+/// stored in the ring, always as a single entry.
+#[derive(Copy, PartialEq, Eq, Clone, Debug)]
+pub enum Stored {
+    /// A single FD, in a [`One`](enum.StashedThing.html#variant.One)
+    /// entry
+    One,
+
+    /// This many FDs, in a
+    /// [`Group`](enum.StashedThing.html#variant.Group) entry
+    Group(usize),
+}
+
+impl Stored {
+    /// The number of FDs that were stored.
+    pub fn fds(&self) -> u64 {
+        match *self {
+            Stored::One => 1,
+            Stored::Group(n) => n as u64,
+        }
+    }
+}
+
 /// A ring buffer containing file descriptors.
 ///
 /// You can stuff FDs in with the [`add`](#method.add) method, and
@@ -25,6 +68,18 @@ pub struct Ring {
 
     /// The number of file descriptors contained in the ring buffer.
     pub count: u64,
+
+    /// The send buffer size that was asked for when creating the
+    /// ring, or `None` for rings that came out of another ring. The
+    /// size the kernel actually granted is
+    /// [`send_buffer_size`](#method.send_buffer_size).
+    pub requested_send_buffer: Option<usize>,
+
+    /// Whether the send buffer size was set with `SO_SNDBUFFORCE`,
+    /// getting around `net.core.wmem_max`.
+    pub send_buffer_forced: bool,
+
+    transport: &'static dyn Transport,
 }
 
 impl fmt::Display for Ring {
@@ -36,8 +91,7 @@ impl fmt::Display for Ring {
 impl Drop for Ring {
     fn drop(&mut self) {
         // println!("Dropping sockets holding {} fds", self.count);
-        unistd::close(self.write).unwrap();
-        unistd::close(self.read).unwrap();
+        self.transport.close(self.read, self.write).unwrap();
     }
 }
 
@@ -53,6 +107,14 @@ pub enum ProtocolError {
 
     /// Expected one FD, got more
     TooManyFDsReceived,
+
+    /// Tried to stash a group with no FDs, or with more than
+    /// [`MAX_FDS_PER_ENTRY`](constant.MAX_FDS_PER_ENTRY.html)
+    BadGroupSize(usize),
+
+    /// The message was cut short (`MSG_TRUNC` or `MSG_CTRUNC`), so
+    /// some of its data or FDs were lost
+    Truncated,
 }
 
 #[derive(Debug)]
@@ -67,6 +129,21 @@ pub enum Error {
     /// A protocol error (e.g., messages on the socket didn't have the
     /// right format)
     Protocol(ProtocolError),
+
+    /// A stashed entry isn't the kind of thing it was supposed to be
+    /// converted to. Contains the expected kind and a description of
+    /// what was found instead.
+    WrongKind(&'static str, String),
+
+    /// The [`watchdog`](../watchdog/index.html) stopped the
+    /// experiment before it could hurt the system.
+    Tripped(watchdog::Tripped),
+
+    /// [`add_all`](struct.Ring.html#method.add_all) failed with the
+    /// contained error, and taking out the entries it had already
+    /// added failed too. Contains how many of them are still in the
+    /// ring.
+    RollbackFailed(Box<Error>, u64),
 }
 
 impl From<nix::Error> for Error {
@@ -83,6 +160,12 @@ impl From<nix::Error> for Error {
     }
 }
 
+impl From<watchdog::Tripped> for Error {
+    fn from(reason: watchdog::Tripped) -> Error {
+        Error::Tripped(reason)
+    }
+}
+
 impl From<num::ParseIntError> for Error {
     fn from(_: num::ParseIntError) -> Error {
         Error::Protocol(ProtocolError::RingFormatError)
@@ -98,19 +181,237 @@ impl From<str::Utf8Error> for Error {
 /// A specialized Result type for fd Ring buffer operations.
 pub type Result<T> = result::Result<T, Error>;
 
+/// The result of converting a [`StashedThing`](enum.StashedThing.html)
+/// into a handle. On failure, the entry comes back unchanged along
+/// with the error, so it can still be closed or converted into
+/// something else.
+pub type ConvertResult<T> = result::Result<T, (Error, StashedThing)>;
+
 // Create a new Ring with a UNIX domain socket pair.
 pub fn new() -> Result<Ring> {
-    use super::unix_socket_pair;
+    with_send_buffer(SEND_BUF_SIZE)
+}
 
-    let (read, write) = try!(unix_socket_pair());
+/// Creates a new Ring, asking the kernel for a send buffer of
+/// `buf_size` bytes (which it may adjust, see
+/// [`send_buffer_size`](struct.Ring.html#method.send_buffer_size)).
+pub fn with_send_buffer(buf_size: usize) -> Result<Ring> {
+    with_options(socket::SockType::Stream, buf_size)
+}
+
+/// Creates a new Ring from a UNIX domain socket pair of type
+/// `sock_type`, asking for a send buffer of `buf_size` bytes.
+///
+/// The kernel clamps the size to
+/// [`max_send_buffer`](fn.max_send_buffer.html) (and Linux doubles
+/// it for bookkeeping), so check
+/// [`send_buffer_size`](struct.Ring.html#method.send_buffer_size) for
+/// what you actually got.
+///
+/// Note that datagram sockets only queue a few messages (on Linux,
+/// `net.unix.max_dgram_qlen`), no matter how large the buffer is.
+pub fn with_options(sock_type: socket::SockType, buf_size: usize) -> Result<Ring> {
+    open(sock_type, buf_size, false)
+}
+
+/// Like [`with_options`](fn.with_options.html), but if `buf_size` is
+/// more than [`max_send_buffer`](fn.max_send_buffer.html), tries to
+/// set it with `SO_SNDBUFFORCE`, which ignores that limit. This needs
+/// `CAP_NET_ADMIN`; without it, the size gets clamped as usual.
+#[cfg(target_os="linux")]
+pub fn with_forced_send_buffer(sock_type: socket::SockType, buf_size: usize) -> Result<Ring> {
+    open(sock_type, buf_size, true)
+}
+
+// (internal) Creates the socket pair and sets its send buffer size,
+// forcing it past wmem_max if asked to and allowed to.
+fn open(sock_type: socket::SockType, buf_size: usize, force: bool) -> Result<Ring> {
+    use super::unix_socket_pair_of_type;
+
+    let (read, write) = unix_socket_pair_of_type(sock_type)?;
+    let mut ring = Ring {
+        read: read,
+        write: write,
+        count: 0,
+        requested_send_buffer: Some(buf_size),
+        send_buffer_forced: false,
+        transport: &transport::SOCKETS,
+    };
     // Adjust limits:
-    let buf_size: usize = SEND_BUF_SIZE;
-    try!(socket::setsockopt(write, socket::sockopt::SndBuf, &buf_size));
-    return Ok(Ring {
+    let over_max = max_send_buffer().map(|max| buf_size > max).unwrap_or(false);
+    if force && over_max {
+        ring.send_buffer_forced = force_send_buffer(write, buf_size)?;
+    }
+    if !ring.send_buffer_forced {
+        socket::setsockopt(write, socket::sockopt::SndBuf, &buf_size)?;
+    }
+    return Ok(ring);
+}
+
+/// Creates a new Ring that keeps its entries in memory instead of a
+/// socket buffer (see [`transport::Memory`](../transport/struct.Memory.html)),
+/// and holds at most `capacity` of them. Good for testing what rings
+/// do without involving the kernel's limits.
+///
+/// Methods that ask the kernel about the socket buffer (like
+/// [`send_buffer_size`](struct.Ring.html#method.send_buffer_size))
+/// fail on in-memory rings.
+pub fn in_memory(capacity: usize) -> Result<Ring> {
+    let (read, write) = transport::MEMORY.open(capacity)?;
+    Ok(Ring {
         read: read,
         write: write,
         count: 0,
+        requested_send_buffer: None,
+        send_buffer_forced: false,
+        transport: &transport::MEMORY,
+    })
+}
+
+// (internal) Sets the send buffer with SO_SNDBUFFORCE, returning
+// false if we're not allowed to.
+#[cfg(target_os="linux")]
+fn force_send_buffer(fd: RawFd, buf_size: usize) -> Result<bool> {
+    match socket::setsockopt(fd, socket::sockopt::SndBufForce, &buf_size) {
+        Ok(()) => Ok(true),
+        Err(nix::Error::Sys(nix::Errno::EPERM)) => Ok(false),
+        Err(e) => Err(Error::Bad(e)),
+    }
+}
+
+#[cfg(not(target_os="linux"))]
+fn force_send_buffer(_fd: RawFd, _buf_size: usize) -> Result<bool> {
+    Ok(false)
+}
+
+/// Returns the largest send buffer size that setting `SO_SNDBUF` can
+/// get you (`net.core.wmem_max`), or `None` if it can't be read
+/// (e.g. on OS X).
+pub fn max_send_buffer() -> Option<usize> {
+    sysctl::read_one("net.core.wmem_max").ok().map(|max| max as usize)
+}
+
+// Linux doubles the send buffer size it's asked for (to make room
+// for its own bookkeeping), and grants that:
+#[cfg(target_os="linux")]
+const SEND_BUFFER_FACTOR: usize = 2;
+
+#[cfg(not(target_os="linux"))]
+const SEND_BUFFER_FACTOR: usize = 1;
+
+/// Creates a new Ring whose send buffer should hold at least `n`
+/// entries of any kind, according to
+/// [`capacity_estimate`](struct.Ring.html#method.capacity_estimate).
+///
+/// The kernel may not grant a buffer that large (on Linux, it's
+/// capped by `net.core.wmem_max`), so check the ring's
+/// `capacity_estimate` if you need to be sure.
+///
+/// # Errors
+/// * `Bad(EINVAL)` - if `n` entries would need more bytes than fit
+///   in a `usize`.
+pub fn with_capacity(n: u64) -> Result<Ring> {
+    let sizes = entry_sizes()?;
+    let largest = *[sizes.one, sizes.pair, sizes.group].iter().max().unwrap();
+    // Entry sizes are measured against the granted size, so ask for
+    // whatever gets us a granted size of `n * largest`:
+    let wanted = match (largest as u64).checked_mul(n) {
+        Some(wanted) if wanted <= usize::MAX as u64 => wanted as usize,
+        _ => { return Err(Error::Bad(nix::Error::Sys(nix::Errno::EINVAL))); }
+    };
+    let rounded_up = if wanted % SEND_BUFFER_FACTOR == 0 { 0 } else { 1 };
+    with_send_buffer(wanted / SEND_BUFFER_FACTOR + rounded_up)
+}
+
+/// How many bytes of a ring's send buffer each kind of entry takes
+/// up, as measured by [`calibrate`](fn.calibrate.html).
+#[derive(Copy, PartialEq, Eq, Clone, Debug)]
+pub struct EntrySizes {
+    /// The size of a single FD entry
+    pub one: usize,
+
+    /// The size of a ring entry
+    pub pair: usize,
+
+    /// The size of a group entry with
+    /// [`MAX_FDS_PER_ENTRY`](constant.MAX_FDS_PER_ENTRY.html) FDs
+    pub group: usize,
+}
+
+/// How many entries of each kind fit in a ring, as returned by
+/// [`capacity_estimate`](struct.Ring.html#method.capacity_estimate).
+#[derive(Copy, PartialEq, Eq, Clone, Debug)]
+pub struct Capacity {
+    /// How many single FDs fit
+    pub one: u64,
+
+    /// How many rings fit
+    pub pair: u64,
+
+    /// How many groups of
+    /// [`MAX_FDS_PER_ENTRY`](constant.MAX_FDS_PER_ENTRY.html) FDs fit
+    pub group: u64,
+}
+
+// (internal) Fills a small ring with `thing` until it's full, and
+// returns how much of the buffer each entry took. If the kernel's
+// in-flight limit stops us before the ring is full, this goes by how
+// much of the buffer is in use instead (which only works on Linux).
+fn measure_entry_size(thing: StashableThing) -> Result<usize> {
+    let mut ring = with_send_buffer(CALIBRATION_BUF_SIZE)?;
+    loop {
+        match ring.add(thing.clone()) {
+            Ok(()) => {}
+            Err(Error::Limit(nix::Error::Sys(nix::Errno::EAGAIN))) if ring.count > 0 => { break; }
+            Err(Error::Limit(nix::Error::Sys(nix::Errno::ETOOMANYREFS))) if ring.count > 0 => {
+                let used = ring.bytes_queued()?;
+                return Ok((used + ring.count as usize - 1) / ring.count as usize);
+            }
+            Err(e) => { return Err(e); }
+        }
+    }
+    let granted = ring.send_buffer_size()?;
+    Ok((granted + ring.count as usize - 1) / ring.count as usize)
+}
+
+/// Measures how much of a ring's send buffer each kind of entry
+/// takes up, by filling small rings until they're full.
+pub fn calibrate() -> Result<EntrySizes> {
+    let (read, write) = unistd::pipe()?;
+    let group = [read; MAX_FDS_PER_ENTRY];
+    let sizes = new().and_then(|ring| {
+        Ok(EntrySizes {
+            one: measure_entry_size(StashableThing::One(read))?,
+            pair: measure_entry_size(StashableThing::Pair(&ring))?,
+            group: measure_entry_size(StashableThing::Group(&group))?,
+        })
     });
+    unistd::close(read)?;
+    unistd::close(write)?;
+    sizes
+}
+
+static ONE_SIZE: AtomicUsize = AtomicUsize::new(0);
+static PAIR_SIZE: AtomicUsize = AtomicUsize::new(0);
+static GROUP_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the entry sizes measured by
+/// [`calibrate`](fn.calibrate.html), calibrating only the first time
+/// it's called.
+pub fn entry_sizes() -> Result<EntrySizes> {
+    let cached = EntrySizes {
+        one: ONE_SIZE.load(Ordering::SeqCst),
+        pair: PAIR_SIZE.load(Ordering::SeqCst),
+        group: GROUP_SIZE.load(Ordering::SeqCst),
+    };
+    if cached.one > 0 && cached.pair > 0 && cached.group > 0 {
+        return Ok(cached);
+    }
+    let sizes = calibrate()?;
+    ONE_SIZE.store(sizes.one, Ordering::SeqCst);
+    PAIR_SIZE.store(sizes.pair, Ordering::SeqCst);
+    GROUP_SIZE.store(sizes.group, Ordering::SeqCst);
+    Ok(sizes)
 }
 
 /// StashableThing enumerates all the things that can go "into" a
@@ -120,6 +421,7 @@ pub fn new() -> Result<Ring> {
 pub enum StashableThing<'a> {
     One(RawFd),
     Pair(&'a Ring),
+    Group(&'a [RawFd]),
 }
 
 
@@ -135,6 +437,29 @@ impl<'a> From<&'a Ring> for StashableThing<'a> {
     }
 }
 
+impl<'a> From<&'a [RawFd]> for StashableThing<'a> {
+    fn from(fds: &'a [RawFd]) -> StashableThing<'a> {
+        StashableThing::Group(fds)
+    }
+}
+
+// Lets you stash borrowed std handles without going through
+// `as_raw_fd()`; the handle stays open & owned by the caller.
+macro_rules! stashable_handle {
+    ($t:ty) => {
+        impl<'a> From<&'a $t> for StashableThing<'a> {
+            fn from(handle: &'a $t) -> StashableThing<'a> {
+                StashableThing::One(handle.as_raw_fd())
+            }
+        }
+    }
+}
+
+stashable_handle!(File);
+stashable_handle!(UnixStream);
+stashable_handle!(UnixListener);
+stashable_handle!(TcpStream);
+
 /// StashedThing enumerates all things that can come of of a
 /// [`Ring`](struct.Ring.html) buffer (say, when iterating).
 #[derive(Clone)]
@@ -145,7 +470,172 @@ pub enum StashedThing {
     /// indicates that the current entry is another ring buffer
     /// (compatibility note: Stashing ring buffers does not work on
     /// BSD-alikes like OS X).
-    Pair(Ring)
+    Pair(Ring),
+
+    /// indicates that the current entry is a group of related file
+    /// descriptors (e.g. both ends of a pipe) that were stored and
+    /// retrieved together.
+    Group(Vec<RawFd>),
+}
+
+impl fmt::Debug for StashedThing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StashedThing::One(fd) => write!(f, "One({})", fd),
+            StashedThing::Pair(ref ring) => write!(f, "Pair({})", ring),
+            StashedThing::Group(ref fds) => write!(f, "Group({:?})", fds),
+        }
+    }
+}
+
+impl StashedThing {
+    /// (internal) Checks that the entry is a single FD whose kind
+    /// satisfies `ok`, handing the entry back if it isn't.
+    fn into_checked_fd<F: Fn(&FdKind) -> bool>(self, expected: &'static str, ok: F) -> ConvertResult<RawFd> {
+        let found = match self {
+            StashedThing::One(fd) => {
+                match kind::kind_of(fd) {
+                    Ok(ref k) if ok(k) => { return Ok(fd); }
+                    Ok(_) => kind::describe(fd).map(|description| format!("{}", description)),
+                    Err(e) => Err(e),
+                }
+            }
+            StashedThing::Pair(ref ring) => Ok(format!("{}", ring)),
+            StashedThing::Group(ref fds) => Ok(format!("a group of {} FDs", fds.len())),
+        };
+        match found {
+            Ok(found) => Err((Error::WrongKind(expected, found), self)),
+            Err(e) => Err((Error::from(e), self)),
+        }
+    }
+
+    /// Converts the entry into a `File`. Regular files, pipes and
+    /// character or block devices are accepted.
+    ///
+    /// # Errors
+    /// * [`WrongKind`](enum.Error.html#variant.WrongKind) - if the
+    ///   entry is anything else (a socket, a directory, an eventfd,
+    ///   ...). The entry is returned with the error, still open.
+    pub fn into_file(self) -> ConvertResult<File> {
+        let fd = self.into_checked_fd("file", |k| {
+            match *k {
+                FdKind::RegularFile | FdKind::Fifo | FdKind::CharDevice | FdKind::BlockDevice => true,
+                _ => false,
+            }
+        })?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Converts the entry into a connected (or unconnected, but not
+    /// listening) UNIX domain stream socket.
+    ///
+    /// # Errors
+    /// * [`WrongKind`](enum.Error.html#variant.WrongKind) - if the
+    ///   entry is anything else. The entry is returned with the
+    ///   error, still open.
+    pub fn into_unix_stream(self) -> ConvertResult<UnixStream> {
+        let fd = self.into_checked_fd("unix stream socket", |k| {
+            match *k {
+                FdKind::Socket(s) => s.is_unix() && s.is_stream() && !s.listening,
+                _ => false,
+            }
+        })?;
+        Ok(unsafe { UnixStream::from_raw_fd(fd) })
+    }
+
+    /// Converts the entry into a listening UNIX domain stream socket.
+    ///
+    /// # Errors
+    /// * [`WrongKind`](enum.Error.html#variant.WrongKind) - if the
+    ///   entry is anything else. The entry is returned with the
+    ///   error, still open.
+    pub fn into_unix_listener(self) -> ConvertResult<UnixListener> {
+        let fd = self.into_checked_fd("listening unix socket", |k| {
+            match *k {
+                FdKind::Socket(s) => s.is_unix() && s.is_stream() && s.listening,
+                _ => false,
+            }
+        })?;
+        Ok(unsafe { UnixListener::from_raw_fd(fd) })
+    }
+
+    /// Converts the entry into a TCP stream (IPv4 or IPv6, not
+    /// listening).
+    ///
+    /// # Errors
+    /// * [`WrongKind`](enum.Error.html#variant.WrongKind) - if the
+    ///   entry is anything else. The entry is returned with the
+    ///   error, still open.
+    pub fn into_tcp_stream(self) -> ConvertResult<TcpStream> {
+        let fd = self.into_checked_fd("tcp stream", |k| {
+            match *k {
+                FdKind::Socket(s) => s.is_inet() && s.is_stream() && !s.listening,
+                _ => false,
+            }
+        })?;
+        Ok(unsafe { TcpStream::from_raw_fd(fd) })
+    }
+}
+
+/// A description of an entry in a [`Ring`](struct.Ring.html), as
+/// returned by [`describe_all`](struct.Ring.html#method.describe_all).
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum EntryDescription {
+    /// A single FD
+    One(Description),
+
+    /// A group of FDs
+    Group(Vec<Description>),
+
+    /// A nested ring, with descriptions of all its entries
+    Ring(Vec<EntryDescription>),
+}
+
+/// Closes all of `fds`, even if closing some of them fails, and
+/// returns the first error.
+pub fn close_all(fds: &[RawFd]) -> Result<()> {
+    let mut closed = Ok(());
+    for &fd in fds {
+        let res = unistd::close(fd);
+        if closed.is_ok() {
+            closed = res;
+        }
+    }
+    Ok(closed?)
+}
+
+// (internal) Describes a copy of an entry that `next` rotated, closing
+// its FDs either way.
+fn describe_entry(thing: StashedThing) -> Result<EntryDescription> {
+    match thing {
+        StashedThing::One(fd) => {
+            let description = kind::describe(fd);
+            unistd::close(fd)?;
+            Ok(EntryDescription::One(description?))
+        }
+        StashedThing::Group(fds) => {
+            let group: Vec<_> = fds.iter().map(|&fd| kind::describe(fd)).collect();
+            close_all(&fds)?;
+            let mut descriptions = vec![];
+            for description in group {
+                descriptions.push(description?);
+            }
+            Ok(EntryDescription::Group(descriptions))
+        }
+        StashedThing::Pair(mut ring) => {
+            Ok(EntryDescription::Ring(ring.describe_all()?))
+        }
+    }
+}
+
+// (internal) Closes the FDs that came out of the ring with `thing`.
+fn discard(thing: StashedThing) -> Result<()> {
+    match thing {
+        StashedThing::One(fd) => { unistd::close(fd)?; }
+        StashedThing::Group(fds) => { close_all(&fds)?; }
+        StashedThing::Pair(ring) => { drop(ring); }
+    }
+    Ok(())
 }
 
 impl<'a> From<&'a StashedThing> for StashableThing<'a> {
@@ -153,7 +643,8 @@ impl<'a> From<&'a StashedThing> for StashableThing<'a> {
     fn from(thing: &'a StashedThing) -> StashableThing<'a> {
         match thing {
             &StashedThing::One(fd) => StashableThing::One(fd),
-            &StashedThing::Pair(ref ring) => StashableThing::Pair(&ring)
+            &StashedThing::Pair(ref ring) => StashableThing::Pair(&ring),
+            &StashedThing::Group(ref fds) => StashableThing::Group(fds.as_slice()),
         }
     }
 }
@@ -166,100 +657,301 @@ impl<'a> Ring {
     /// * [`Bad(nix::Error)`](enum.Error.html#variant.Bad) - if any unforeseen condition occurs
     /// * [`Limit(nix::Error)`](enum.Error.html#variant.Limit) - if
     ///   the socket would block or any other limit runs over.
+    /// * [`Protocol(BadGroupSize)`](enum.ProtocolError.html#variant.BadGroupSize) -
+    ///   if a group is empty or too large to fit in one entry.
+    /// * `Bad(EINVAL)` - if an [`in_memory`](fn.in_memory.html) ring
+    ///   is added to a ring that isn't (see the
+    ///   [`transport`](../transport/index.html) module).
     pub fn add<T: Into<StashableThing<'a>>>(&mut self, thing: T) -> Result<()> {
-        let n = try!(self.insert(thing));
+        let n = self.insert(thing)?;
         self.count += n;
         Ok(())
     }
 
+    /// Adds an owned handle (a `File`, `UnixStream`, etc.) to the
+    /// Ring and closes it: the only copy of the FD then lives in the
+    /// ring.
+    ///
+    /// The handle is consumed and closed in any case (success or
+    /// error); errors are the same as for [`add`](#method.add).
+    pub fn add_owned<T: IntoRawFd>(&mut self, handle: T) -> Result<()> {
+        let fd = handle.into_raw_fd();
+        let result = self.add(fd);
+        let closed = unistd::close(fd);
+        result?;
+        closed?;
+        Ok(())
+    }
+
+    /// Adds all of `fds` to the Ring in a single entry (a
+    /// [`Group`](enum.StashableThing.html#variant.Group), or a
+    /// [`One`](enum.StashableThing.html#variant.One) entry if there's
+    /// only one FD): either they all go in, or none of them do.
+    /// Closing the FDs is left to the caller.
+    ///
+    /// Returns what was stored; errors are the same as for
+    /// [`add`](#method.add).
+    pub fn add_together(&mut self, fds: &[RawFd]) -> Result<Stored> {
+        if fds.len() == 1 {
+            self.add(fds[0])?;
+            return Ok(Stored::One);
+        }
+        self.add(fds)?;
+        Ok(Stored::Group(fds.len()))
+    }
+
+    /// Adds each of `things` to the Ring as its own entry: either all
+    /// of them go in, or none do. If adding one fails, the entries
+    /// this already added are taken out again (and their FDs closed),
+    /// leaving the ring as it was before, and the error is returned.
+    ///
+    /// Taking them out means receiving every entry in the ring, and
+    /// that can fail too, especially if adding failed because the
+    /// process is out of FDs: the kernel then drops the FDs it can't
+    /// hand over (`MSG_CTRUNC`), and the entry they were in is lost.
+    /// If that happens, this returns `RollbackFailed` with the
+    /// original error and how many of the new entries are still in
+    /// the ring. The ring's `count` stays accurate (entries that were
+    /// lost are closed and no longer counted), but its order isn't
+    /// kept.
+    ///
+    /// Closing the FDs passed in is left to the caller; other errors
+    /// are the same as for [`add`](#method.add).
+    pub fn add_all<T, I>(&mut self, things: I) -> Result<()>
+        where T: Into<StashableThing<'a>>, I: IntoIterator<Item=T>
+    {
+        let before = self.count;
+        for thing in things {
+            if let Err(e) = self.add(thing) {
+                let added = self.count - before;
+                return match self.roll_back(before, added) {
+                    Ok(()) => Err(e),
+                    Err(left) => Err(Error::RollbackFailed(Box::new(e), left)),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// (internal) Takes the `added` newest entries out of the ring,
+    /// keeping the `before` ones that were there first (in order). If
+    /// that fails, returns how many of the new entries are left.
+    fn roll_back(&mut self, before: u64, added: u64) -> result::Result<(), u64> {
+        // How many of the old entries are still in the ring; the rest
+        // of `count` are new ones:
+        let mut old = before;
+        // Move the old entries behind the new ones...
+        for _ in 0..before {
+            let count = self.count;
+            let rotated = self.next();
+            // An entry that `next` lost is an old one:
+            old -= count - self.count;
+            match rotated {
+                Ok(thing) => { discard(thing).map_err(|_| self.count - old)?; }
+                Err(_) => { return Err(self.count - old); }
+            }
+        }
+        // ...which are at the front now:
+        for _ in 0..added {
+            match self.pop() {
+                Ok(thing) => { discard(thing).map_err(|_| self.count - old)?; }
+                Err(_) => { return Err(self.count - old); }
+            }
+        }
+        Ok(())
+    }
+
     /// (internal) Add an FD to the ring, sending it down the `.write`
     /// end, and returns the number of entries made
     fn insert<T: Into<StashableThing<'a>>>(&self, thing: T) -> Result<u64> {
         let mut msg = String::from("");
         let mut fds: Vec<RawFd> = vec![];
-        let mut buf: Vec<IoVec<&[u8]>> = vec![];
         match thing.into() {
             StashableThing::One(fd) => {
-                msg.push('!');
+                msg.push_str(str::from_utf8(wire::ONE_MARKER).unwrap());
                 fds.push(fd);
             }
             StashableThing::Pair(ring) => {
+                if ring.transport.in_process() && !self.transport.in_process() {
+                    return Err(Error::Bad(nix::Error::Sys(nix::Errno::EINVAL)));
+                }
                 msg.push_str(format!("{}", ring.count).as_str());
                 fds.push(ring.read);
                 fds.push(ring.write);
             }
+            StashableThing::Group(group) => {
+                if group.len() == 0 || group.len() > MAX_FDS_PER_ENTRY {
+                    return Err(Error::Protocol(ProtocolError::BadGroupSize(group.len())));
+                }
+                msg.push_str(str::from_utf8(wire::GROUP_MARKER).unwrap());
+                fds.extend_from_slice(group);
+            }
         }
-        buf.push(IoVec::from_slice(msg.as_bytes()));
-        let cmsgs = vec![socket::ControlMessage::ScmRights(fds.as_slice())];
-        try!(socket::sendmsg(self.write,
-                             &buf.as_slice(),
-                             cmsgs.as_slice(),
-                             socket::MsgFlags::empty(),
-                             None));
+        self.transport.send(self.write, msg.as_bytes(), &fds)?;
         Ok(1)
     }
 
     /// Removes and returns the head of the fd ring, updating count.
     pub fn pop(&mut self) -> Result<StashedThing> {
-        let thing = try!(self.remove());
+        let thing = self.remove()?;
         self.count -= 1;
         Ok(thing)
     }
 
-    /// (internal) Removes and returns the head of the ring from `.read`.
-    fn remove(&self) -> Result<StashedThing> {
+    /// (internal) Removes and returns the head of the ring from
+    /// `.read`. Once a message was received, it's out of the ring, so
+    /// if it can't be turned into an entry (its FDs are closed and)
+    /// it's taken out of the count.
+    fn remove(&mut self) -> Result<StashedThing> {
         // I assume we have no more than a 10^1023 FDs in there, but haha.
         let mut backing_buf: Vec<u8> = vec![0;1024];
 
-        // TODO: deal with the constant 15 here.
-        let mut cmsg: socket::CmsgSpace<([RawFd; 15])> = socket::CmsgSpace::new();
-        let iov = IoVec::from_mut_slice(backing_buf.as_mut_slice());
-        let mut iovs = vec![iov];
-        let msg = try!(socket::recvmsg(self.read,
-                                       &mut iovs.as_mut_slice(),
-                                       Some(&mut cmsg),
-                                       socket::MsgFlags::empty()));
-
-        let read_buffer: &[u8] = iovs[0].as_slice();
-        let read_bytes: &[u8] = &read_buffer[..msg.bytes];
-        match msg.cmsgs().next() {
-            Some(socket::ControlMessage::ScmRights(fds)) => {
-                // TODO: this could probably handle the case of multiple FDs via buffers
-                match fds.len() {
-                    1 => {
-                        let fd = fds[0];
-                        let thing = StashedThing::One(fd);
-                        Ok(thing)
-                    }
-                    2 => {
-                        let count_str = try!(str::from_utf8(read_bytes));
-                        let count: u64 = try!(u64::from_str(count_str));
-                        let ring = Ring{
-                            read: fds[0],
-                            write: fds[1],
-                            count: count
-                        };
-                        Ok(StashedThing::Pair(ring))
+        let received = self.transport.recv(self.read, &mut backing_buf)?;
+        let thing = self.to_entry(&backing_buf[..received.bytes], received.fds, received.flags);
+        if thing.is_err() {
+            self.count = self.count.saturating_sub(1);
+        }
+        thing
+    }
+
+    /// (internal) Turns a received message into an entry, closing its
+    /// FDs if it isn't one.
+    fn to_entry(&self, payload: &[u8], fds: Vec<RawFd>, flags: socket::MsgFlags) -> Result<StashedThing> {
+        match wire::decode(payload, fds, flags) {
+            Ok(Entry::One(fd)) => Ok(StashedThing::One(fd)),
+            Ok(Entry::Group(fds)) => Ok(StashedThing::Group(fds)),
+            Ok(Entry::Ring { read, write, count }) => {
+                // Rings of either kind can be stashed in in-memory
+                // rings, so don't assume it's the same as ours:
+                let transport = match transport::of(read) {
+                    Ok(transport) => transport,
+                    Err(e) => {
+                        close_all(&[read, write])?;
+                        return Err(e);
                     }
-                    0 => Err(Error::Protocol(ProtocolError::NoFDReceived(1))),
-                    _ => Err(Error::Protocol(ProtocolError::TooManyFDsReceived)),
-                }
+                };
+                let ring = Ring{
+                    read: read,
+                    write: write,
+                    count: count,
+                    requested_send_buffer: None,
+                    send_buffer_forced: false,
+                    transport: transport,
+                };
+                Ok(StashedThing::Pair(ring))
+            }
+            Err(malformed) => {
+                close_all(&malformed.fds)?;
+                Err(Error::Protocol(malformed.error))
             }
-            Some(_) => { panic!("Received something other than ScmRights! Wat."); }
-            _ => Err(Error::Protocol(ProtocolError::NoFDReceived(2)))
         }
     }
 
-    fn next(&self) -> Result<StashedThing> {
-        let thing = try!(self.remove());
-        try!(self.insert(&thing));
+    /// (internal) Moves the head of the ring to its back, and returns
+    /// a copy of it. If it can't be put back, it's closed and taken
+    /// out of the count, and the error is returned.
+    fn next(&mut self) -> Result<StashedThing> {
+        let thing = self.remove()?;
+        if let Err(e) = self.insert(&thing) {
+            self.count -= 1;
+            let _ = discard(thing);
+            return Err(e);
+        }
         Ok(thing)
     }
 
+    /// Describes every entry in the ring (see
+    /// [`kind::describe`](../kind/fn.describe.html)), descending into
+    /// nested rings. The ring's contents and order are unchanged
+    /// afterwards.
+    ///
+    /// If describing an entry fails, the rest of the ring still gets
+    /// rotated back into place before the error is returned. Only if
+    /// moving an entry from the front of the ring to the back fails
+    /// is the ring left rotated; that entry is lost then, and no
+    /// longer counted in `count`.
+    pub fn describe_all(&mut self) -> Result<Vec<EntryDescription>> {
+        let mut descriptions = vec![];
+        let mut failed = None;
+        for _ in 0..self.count {
+            let thing = self.next()?;
+            if failed.is_some() {
+                discard(thing)?;
+                continue;
+            }
+            match describe_entry(thing) {
+                Ok(description) => descriptions.push(description),
+                Err(e) => failed = Some(e),
+            }
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(descriptions),
+        }
+    }
+
+    /// Returns the ring's own FDs: its read end and its write end.
+    pub fn raw_fds(&self) -> (RawFd, RawFd) {
+        (self.read, self.write)
+    }
+
+    /// Returns the size of the ring's send buffer, as granted by the
+    /// kernel.
+    pub fn send_buffer_size(&self) -> Result<usize> {
+        Ok(socket::getsockopt(self.write, socket::sockopt::SndBuf)?)
+    }
+
+    /// Returns true if the kernel granted a smaller send buffer than
+    /// was asked for (usually because of `net.core.wmem_max`). Linux
+    /// doubles the size it's asked for, so there, this compares the
+    /// granted size against twice the requested one.
+    pub fn send_buffer_clamped(&self) -> Result<bool> {
+        match self.requested_send_buffer {
+            Some(requested) => {
+                // No buffer could be as big as an overflowing request:
+                match requested.checked_mul(SEND_BUFFER_FACTOR) {
+                    Some(wanted) => Ok(self.send_buffer_size()? < wanted),
+                    None => Ok(true),
+                }
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Returns how many bytes of kernel memory the entries in the
+    /// ring take up (what `SIOCOUTQ` says about the sending socket).
+    #[cfg(target_os="linux")]
+    pub fn bytes_queued(&self) -> Result<usize> {
+        let mut queued: libc::c_int = 0;
+        let res = unsafe { libc::ioctl(self.write, libc::TIOCOUTQ, &mut queued) };
+        nix::Errno::result(res)?;
+        Ok(queued as usize)
+    }
+
+    /// Returns how many bytes of kernel memory the entries in the
+    /// ring take up. Only works on Linux.
+    #[cfg(not(target_os="linux"))]
+    pub fn bytes_queued(&self) -> Result<usize> {
+        Err(Error::Bad(nix::Error::Sys(nix::Errno::EOPNOTSUPP)))
+    }
+
+    /// Estimates how many entries of each kind fit in the ring in
+    /// total (including the ones already in it), from the size of
+    /// its send buffer and the [`entry_sizes`](fn.entry_sizes.html).
+    pub fn capacity_estimate(&self) -> Result<Capacity> {
+        let granted = self.send_buffer_size()? as u64;
+        let sizes = entry_sizes()?;
+        Ok(Capacity {
+            one: granted / sizes.one as u64,
+            pair: granted / sizes.pair as u64,
+            group: granted / sizes.group as u64,
+        })
+    }
+
     /// Returns an iterator on the FDs contained in the ring buffer
-    pub fn iter(&self) -> RingIter {
+    pub fn iter(&mut self) -> RingIter<'_> {
         RingIter {
-            ring: &self,
+            ring: self,
             offset: 0,
         }
     }
@@ -267,7 +959,7 @@ impl<'a> Ring {
 
 /// An iterator over the File descriptors contained in an FD ring buffer
 pub struct RingIter<'a> {
-    ring: &'a Ring,
+    ring: &'a mut Ring,
     offset: u64,
 }
 
@@ -292,18 +984,22 @@ impl<'a> Iterator for RingIter<'a> {
 
 #[test]
 fn it_can_create_a_ringbuffer() {
+    let _leaks = ::leaks::guard();
     let ring = new().unwrap();
     println!("Got a ring: {}", ring);
 }
 
 #[test]
 fn adding_to_ring_works() {
+    let _leaks = ::leaks::guard();
     let mut ring = new().unwrap();
     let (one, two) = super::unix_socket_pair().unwrap();
     ring.add(one).unwrap();
     assert_eq!(1, ring.count);
     ring.add(two).unwrap();
     assert_eq!(2, ring.count);
+    unistd::close(one).unwrap();
+    unistd::close(two).unwrap();
 
     let other_ring = new().unwrap();
     ring.add(&other_ring).unwrap();
@@ -311,11 +1007,577 @@ fn adding_to_ring_works() {
 
     let received = ring.pop().unwrap();
     match received {
-        StashedThing::One(_) => {
+        StashedThing::One(fd) => {
             println!("Yay!");
+            unistd::close(fd).unwrap();
         }
         _ => {
             panic!("Huh!");
         }
     }
 }
+
+#[test]
+fn adding_a_group_to_ring_works() {
+    let _leaks = ::leaks::guard();
+    let mut ring = new().unwrap();
+    let (one, two) = super::unix_socket_pair().unwrap();
+    let (three, four) = super::unix_socket_pair().unwrap();
+    ring.add(&[one, two, three][..]).unwrap();
+    ring.add(&[four][..]).unwrap();
+    assert_eq!(2, ring.count);
+
+    for &expected in [3, 1].iter() {
+        match ring.pop().unwrap() {
+            StashedThing::Group(fds) => {
+                assert_eq!(expected, fds.len());
+                for fd in fds {
+                    unistd::close(fd).unwrap();
+                }
+            }
+            _ => {
+                panic!("Expected a group!");
+            }
+        }
+    }
+    for fd in vec![one, two, three, four] {
+        unistd::close(fd).unwrap();
+    }
+}
+
+#[test]
+fn adding_std_handles_works() {
+    let _leaks = ::leaks::guard();
+    let mut ring = new().unwrap();
+    let (one, two) = UnixStream::pair().unwrap();
+    ring.add(&one).unwrap();
+    ring.add_owned(two).unwrap();
+    assert_eq!(2, ring.count);
+
+    while ring.count > 0 {
+        match ring.pop().unwrap() {
+            StashedThing::One(fd) => {
+                unistd::close(fd).unwrap();
+            }
+            _ => {
+                panic!("Expected a single FD!");
+            }
+        }
+    }
+}
+
+#[test]
+fn adding_owned_handles_closes_them_on_errors() {
+    use faults;
+    use faults::Syscall;
+
+    let _leaks = ::leaks::guard();
+    let mut ring = new().unwrap();
+    let (one, two) = UnixStream::pair().unwrap();
+    let fd = two.as_raw_fd();
+    {
+        let _injected = faults::inject(Syscall::SendMsg, 1, nix::Errno::ETOOMANYREFS);
+        match ring.add_owned(two) {
+            Err(Error::Limit(nix::Error::Sys(nix::Errno::ETOOMANYREFS))) => {}
+            other => { panic!("Expected ETOOMANYREFS, got {:?}", other); }
+        }
+    }
+    assert_eq!(Err(nix::Error::Sys(nix::Errno::EBADF)), kind::kind_of(fd).map(|_| ()));
+    assert_eq!(0, ring.count);
+    drop(one);
+}
+
+#[test]
+fn converting_popped_entries_works() {
+    use std::io::{Read, Write};
+
+    let _leaks = ::leaks::guard();
+    let mut ring = new().unwrap();
+    let (mut one, two) = UnixStream::pair().unwrap();
+    ring.add_owned(two).unwrap();
+    ring.add(&one).unwrap();
+
+    let mut two = ring.pop().unwrap().into_unix_stream().unwrap();
+    one.write_all(b"hi").unwrap();
+    let mut buf = [0; 2];
+    two.read_exact(&mut buf).unwrap();
+    assert_eq!(b"hi", &buf);
+
+    match ring.pop().unwrap().into_file() {
+        Err((Error::WrongKind(..), thing)) => { discard(thing).unwrap(); }
+        _ => { panic!("A socket shouldn't convert into a file"); }
+    }
+}
+
+#[test]
+fn failing_to_describe_leaves_the_ring_in_order() {
+    use faults;
+    use faults::Syscall;
+
+    let _leaks = ::leaks::guard();
+    let mut ring = new().unwrap();
+    let (read, write) = unistd::pipe().unwrap();
+    let mut inner = new().unwrap();
+    inner.add(write).unwrap();
+    ring.add(read).unwrap();
+    ring.add(&inner).unwrap();
+    drop(inner);
+    ring.add(write).unwrap();
+    {
+        // The first receive from the inner ring fails:
+        let _injected = faults::inject(Syscall::RecvMsg, 3, nix::Errno::EAGAIN);
+        match ring.describe_all() {
+            Err(Error::Limit(nix::Error::Sys(nix::Errno::EAGAIN))) => {}
+            other => { panic!("Expected EAGAIN, got {:?}", other); }
+        }
+    }
+    assert_eq!(3, ring.describe_all().unwrap().len());
+    match ring.pop().unwrap() {
+        StashedThing::One(fd) => {
+            assert_eq!(kind::describe(read).unwrap(), kind::describe(fd).unwrap());
+            unistd::close(fd).unwrap();
+        }
+        _ => { panic!("Expected the read end first"); }
+    }
+    match ring.pop().unwrap() {
+        StashedThing::Pair(inner) => { assert_eq!(1, inner.count); }
+        _ => { panic!("Expected the inner ring second"); }
+    }
+    discard(ring.pop().unwrap()).unwrap();
+    unistd::close(read).unwrap();
+    unistd::close(write).unwrap();
+}
+
+#[test]
+fn failing_to_rotate_while_describing_drops_the_entry() {
+    use faults;
+    use faults::Syscall;
+
+    let _leaks = ::leaks::guard();
+    let mut ring = new().unwrap();
+    let (read, write) = unistd::pipe().unwrap();
+    ring.add(read).unwrap();
+    ring.add(write).unwrap();
+    {
+        // Putting the first entry back fails:
+        let _injected = faults::inject(Syscall::SendMsg, 1, nix::Errno::EINVAL);
+        match ring.describe_all() {
+            Err(Error::Bad(nix::Error::Sys(nix::Errno::EINVAL))) => {}
+            other => { panic!("Expected EINVAL, got {:?}", other); }
+        }
+    }
+    // The read end is gone (and closed, or the guard would notice):
+    assert_eq!(1, ring.count);
+    match ring.pop().unwrap() {
+        StashedThing::One(fd) => {
+            assert_eq!(kind::describe(write).unwrap(), kind::describe(fd).unwrap());
+            unistd::close(fd).unwrap();
+        }
+        _ => { panic!("Expected the write end"); }
+    }
+    unistd::close(read).unwrap();
+    unistd::close(write).unwrap();
+}
+
+#[test]
+fn only_files_convert_into_files() {
+    use std::fs;
+
+    let _leaks = ::leaks::guard();
+    let mut ring = new().unwrap();
+    let dir = fs::File::open("/").unwrap();
+    let expected = format!("{}", kind::describe(dir.as_raw_fd()).unwrap());
+    ring.add_owned(dir).unwrap();
+    match ring.pop().unwrap().into_file() {
+        Err((Error::WrongKind("file", found), StashedThing::One(fd))) => {
+            assert_eq!(expected, found);
+            // The directory is still open:
+            assert_eq!(expected, format!("{}", kind::describe(fd).unwrap()));
+            unistd::close(fd).unwrap();
+        }
+        other => { panic!("A directory shouldn't convert into a file, got {:?}", other.is_ok()); }
+    }
+
+    let (read, write) = unistd::pipe().unwrap();
+    ring.add(read).unwrap();
+    unistd::close(read).unwrap();
+    drop(ring.pop().unwrap().into_file().unwrap());
+    unistd::close(write).unwrap();
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn eventfds_dont_convert_into_files() {
+    use nix::sys::eventfd;
+
+    let _leaks = ::leaks::guard();
+    let mut ring = new().unwrap();
+    let fd = eventfd::eventfd(0, eventfd::EventFdFlag::empty()).unwrap();
+    ring.add(fd).unwrap();
+    unistd::close(fd).unwrap();
+    match ring.pop().unwrap().into_file() {
+        Err((Error::WrongKind("file", found), thing)) => {
+            assert!(found.contains("eventfd"), "{}", found);
+            discard(thing).unwrap();
+        }
+        other => { panic!("An eventfd shouldn't convert into a file, got {:?}", other.is_ok()); }
+    }
+}
+
+#[test]
+fn describing_a_ring_works() {
+    let _leaks = ::leaks::guard();
+    let mut ring = new().unwrap();
+    let (read, write) = unistd::pipe().unwrap();
+    ring.add(read).unwrap();
+    let mut inner = new().unwrap();
+    inner.add(&[read, write][..]).unwrap();
+    ring.add(&inner).unwrap();
+    unistd::close(read).unwrap();
+    unistd::close(write).unwrap();
+
+    let descriptions = ring.describe_all().unwrap();
+    assert_eq!(2, descriptions.len());
+    match descriptions[0] {
+        EntryDescription::One(Description::Pipe(_)) => {}
+        ref other => { panic!("Expected a pipe, got {:?}", other); }
+    }
+    match descriptions[1] {
+        EntryDescription::Ring(ref entries) => {
+            assert_eq!(1, entries.len());
+        }
+        ref other => { panic!("Expected a ring, got {:?}", other); }
+    }
+    // Describing doesn't consume anything:
+    assert_eq!(descriptions, ring.describe_all().unwrap());
+}
+
+#[test]
+fn closing_all_keeps_going_after_errors() {
+    let _leaks = ::leaks::guard();
+    let (read, write) = unistd::pipe().unwrap();
+    match close_all(&[read, -1, write]) {
+        Err(Error::Bad(nix::Error::Sys(nix::Errno::EBADF))) => {}
+        other => { panic!("Expected EBADF, got {:?}", other); }
+    }
+    // The guard would catch `write` if it was still open.
+}
+
+#[test]
+fn huge_capacities_are_refused() {
+    match with_capacity(u64::MAX) {
+        Err(Error::Bad(nix::Error::Sys(nix::Errno::EINVAL))) => {}
+        other => { panic!("Expected EINVAL, got {:?}", other.is_ok()); }
+    }
+}
+
+#[test]
+fn capacity_estimates_are_close() {
+    use std::cmp;
+
+    let _leaks = ::leaks::guard();
+    let mut ring = with_capacity(50).unwrap();
+    let estimate = ring.capacity_estimate().unwrap();
+    let fewest = cmp::min(estimate.one, cmp::min(estimate.pair, estimate.group));
+    if ring.send_buffer_clamped().unwrap() {
+        // A low net.core.wmem_max got in the way:
+        assert!(fewest < 50);
+    } else {
+        // The largest kind of entry should fit about 50 times, not
+        // 100 (Linux doubles the buffer size it's asked for):
+        assert!(fewest >= 50 && fewest < 60, "{:?}", estimate);
+    }
+
+    let (read, write) = unistd::pipe().unwrap();
+    let mut filled = true;
+    loop {
+        match ring.add(read) {
+            Ok(()) => {}
+            Err(Error::Limit(nix::Error::Sys(nix::Errno::EAGAIN))) => { break; }
+            Err(Error::Limit(nix::Error::Sys(nix::Errno::ETOOMANYREFS))) => {
+                // Unprivileged with a low RLIMIT_NOFILE, we can't
+                // fill the ring (see the inflight module):
+                filled = false;
+                break;
+            }
+            Err(e) => { panic!("Unexpected {:?}", e); }
+        }
+    }
+    if filled {
+        assert!(estimate.one <= ring.count);
+        assert!(estimate.one >= ring.count * 9 / 10);
+    } else {
+        // We stopped before the ring was full, so the estimate has
+        // to leave room for at least what we got in:
+        assert!(ring.count > 0);
+        assert!(estimate.one >= ring.count);
+        assert!(ring.bytes_queued().unwrap() <= ring.send_buffer_size().unwrap());
+    }
+    unistd::close(read).unwrap();
+    unistd::close(write).unwrap();
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn send_buffers_are_clamped_to_wmem_max() {
+    let _leaks = ::leaks::guard();
+    let max = max_send_buffer().unwrap();
+    let ring = with_send_buffer(max * 4).unwrap();
+    assert_eq!(Some(max * 4), ring.requested_send_buffer);
+    assert!(!ring.send_buffer_forced);
+    assert_eq!(max * 2, ring.send_buffer_size().unwrap());
+    assert!(ring.send_buffer_clamped().unwrap());
+
+    // Linux doubling the size it got doesn't hide that it was capped:
+    let ring = with_send_buffer(max + 1000).unwrap();
+    assert_eq!(max * 2, ring.send_buffer_size().unwrap());
+    assert!(ring.send_buffer_clamped().unwrap());
+    let ring = with_send_buffer(max).unwrap();
+    assert!(!ring.send_buffer_clamped().unwrap());
+
+    // Only works with CAP_NET_ADMIN; either way, we should know what
+    // we got:
+    let forced = with_forced_send_buffer(socket::SockType::Stream, max * 4).unwrap();
+    if forced.send_buffer_forced {
+        assert_eq!(max * 8, forced.send_buffer_size().unwrap());
+        assert!(!forced.send_buffer_clamped().unwrap());
+    } else {
+        assert!(forced.send_buffer_clamped().unwrap());
+    }
+
+    // Requests too big to double are clamped, too:
+    let mut ring = new().unwrap();
+    ring.requested_send_buffer = Some(usize::MAX);
+    assert!(ring.send_buffer_clamped().unwrap());
+}
+
+#[test]
+fn adding_all_rolls_back_on_failure() {
+    let _leaks = ::leaks::guard();
+    let mut ring = new().unwrap();
+    let (one, two) = super::unix_socket_pair().unwrap();
+    ring.add(one).unwrap();
+    ring.add(&[one, two][..]).unwrap();
+
+    let empty: &[RawFd] = &[];
+    let just_one = [one];
+    let batch = vec![StashableThing::One(two), StashableThing::Group(&just_one), StashableThing::Group(empty)];
+    match ring.add_all(batch) {
+        Err(Error::Protocol(ProtocolError::BadGroupSize(0))) => {}
+        other => { panic!("Expected the empty group to fail, got {:?}", other); }
+    }
+    assert_eq!(2, ring.count);
+    match ring.pop().unwrap() {
+        StashedThing::One(fd) => { unistd::close(fd).unwrap(); }
+        _ => { panic!("The first entry should still be first"); }
+    }
+    match ring.pop().unwrap() {
+        StashedThing::Group(fds) => {
+            assert_eq!(2, fds.len());
+            for fd in fds {
+                unistd::close(fd).unwrap();
+            }
+        }
+        _ => { panic!("The second entry should still be second"); }
+    }
+    assert_eq!(0, ring.count);
+
+    ring.add_all(vec![one, two]).unwrap();
+    assert_eq!(2, ring.count);
+    unistd::close(one).unwrap();
+    unistd::close(two).unwrap();
+}
+
+#[test]
+fn adding_all_rolls_back_when_the_ring_fills_up() {
+    let _leaks = ::leaks::guard();
+    let mut ring = with_send_buffer(CALIBRATION_BUF_SIZE).unwrap();
+    let (read, write) = unistd::pipe().unwrap();
+    ring.add(write).unwrap();
+    let too_many = vec![read; 10000];
+    match ring.add_all(too_many) {
+        Err(Error::Limit(nix::Error::Sys(nix::Errno::EAGAIN))) => {}
+        other => { panic!("Expected the ring to fill up, got {:?}", other); }
+    }
+    assert_eq!(1, ring.count);
+    match ring.pop().unwrap() {
+        StashedThing::One(fd) => {
+            assert_eq!(kind::describe(write).unwrap(), kind::describe(fd).unwrap());
+            unistd::close(fd).unwrap();
+        }
+        _ => { panic!("Expected the pipe's write end"); }
+    }
+    // Nothing's left behind:
+    match ring.pop() {
+        Err(Error::Limit(nix::Error::Sys(nix::Errno::EAGAIN))) => {}
+        other => { panic!("Expected an empty ring, got {:?}", other.is_ok()); }
+    }
+    unistd::close(read).unwrap();
+    unistd::close(write).unwrap();
+}
+
+#[test]
+fn failing_to_roll_back_keeps_the_original_error() {
+    use faults;
+    use faults::Syscall;
+
+    let _leaks = ::leaks::guard();
+    let mut ring = new().unwrap();
+    let (read, write) = unistd::pipe().unwrap();
+    ring.add(read).unwrap();
+
+    let empty: &[RawFd] = &[];
+    let batch = vec![StashableThing::One(write), StashableThing::One(write), StashableThing::Group(empty)];
+    {
+        // Rotating the old entry takes the first receive, so the
+        // rollback fails when taking out the first new entry:
+        let _injected = faults::inject(Syscall::RecvMsg, 2, nix::Errno::EAGAIN);
+        match ring.add_all(batch) {
+            Err(Error::RollbackFailed(e, 2)) => {
+                match *e {
+                    Error::Protocol(ProtocolError::BadGroupSize(0)) => {}
+                    other => { panic!("Expected the original error, got {:?}", other); }
+                }
+            }
+            other => { panic!("Expected the rollback to fail, got {:?}", other); }
+        }
+    }
+    assert_eq!(3, ring.count);
+    while ring.count > 0 {
+        discard(ring.pop().unwrap()).unwrap();
+    }
+    unistd::close(read).unwrap();
+    unistd::close(write).unwrap();
+}
+
+#[test]
+fn failing_to_rotate_during_a_rollback_loses_only_that_entry() {
+    use faults;
+    use faults::Syscall;
+
+    let _leaks = ::leaks::guard();
+    let mut ring = new().unwrap();
+    let (read, write) = unistd::pipe().unwrap();
+    ring.add(read).unwrap();
+
+    let empty: &[RawFd] = &[];
+    let batch = vec![StashableThing::One(write), StashableThing::One(write), StashableThing::Group(empty)];
+    {
+        // The two new entries take the first two sends, so putting
+        // the old entry back behind them fails:
+        let _injected = faults::inject(Syscall::SendMsg, 3, nix::Errno::EINVAL);
+        match ring.add_all(batch) {
+            Err(Error::RollbackFailed(e, 2)) => {
+                match *e {
+                    Error::Protocol(ProtocolError::BadGroupSize(0)) => {}
+                    other => { panic!("Expected the original error, got {:?}", other); }
+                }
+            }
+            other => { panic!("Expected the rollback to fail, got {:?}", other); }
+        }
+    }
+    // Only the new entries are left (the old one was closed, or the
+    // guard would notice):
+    assert_eq!(2, ring.count);
+    for _ in 0..2 {
+        match ring.pop().unwrap() {
+            StashedThing::One(fd) => {
+                assert_eq!(kind::describe(write).unwrap(), kind::describe(fd).unwrap());
+                unistd::close(fd).unwrap();
+            }
+            _ => { panic!("Expected the write end"); }
+        }
+    }
+    unistd::close(read).unwrap();
+    unistd::close(write).unwrap();
+}
+
+#[test]
+fn failed_sends_and_receives_leave_the_ring_alone() {
+    use faults;
+    use faults::Syscall;
+
+    let _leaks = ::leaks::guard();
+    let mut ring = new().unwrap();
+    let (read, write) = unistd::pipe().unwrap();
+    {
+        let _injected = faults::inject(Syscall::SendMsg, 1, nix::Errno::ETOOMANYREFS);
+        match ring.add(read) {
+            Err(Error::Limit(nix::Error::Sys(nix::Errno::ETOOMANYREFS))) => {}
+            other => { panic!("Expected ETOOMANYREFS, got {:?}", other); }
+        }
+        assert_eq!(0, ring.count);
+    }
+    {
+        let _injected = faults::inject(Syscall::SendMsg, 2, nix::Errno::EINVAL);
+        ring.add(read).unwrap();
+        match ring.add(read) {
+            Err(Error::Bad(nix::Error::Sys(nix::Errno::EINVAL))) => {}
+            other => { panic!("Expected EINVAL, got {:?}", other); }
+        }
+        assert_eq!(1, ring.count);
+    }
+    {
+        let _injected = faults::inject(Syscall::RecvMsg, 1, nix::Errno::EAGAIN);
+        match ring.pop() {
+            Err(Error::Limit(nix::Error::Sys(nix::Errno::EAGAIN))) => {}
+            other => { panic!("Expected EAGAIN, got {:?}", other.is_ok()); }
+        }
+        assert_eq!(1, ring.count);
+    }
+    // The entry is still there:
+    discard(ring.pop().unwrap()).unwrap();
+    assert_eq!(0, ring.count);
+    unistd::close(read).unwrap();
+    unistd::close(write).unwrap();
+}
+
+// (test) Sends a message that `add` would never send down a fresh
+// ring, and returns what `remove` makes of it.
+#[cfg(test)]
+fn remove_raw(payload: &[u8], fds: &[RawFd]) -> Result<StashedThing> {
+    use nix::sys::uio::IoVec;
+
+    let mut ring = new().unwrap();
+    let buf = [IoVec::from_slice(payload)];
+    let rights = [socket::ControlMessage::ScmRights(fds)];
+    let cmsgs: &[socket::ControlMessage] = if fds.is_empty() { &[] } else { &rights };
+    socket::sendmsg(ring.write, &buf, cmsgs, socket::MsgFlags::empty(), None).unwrap();
+    ring.remove()
+}
+
+#[test]
+fn removing_malformed_entries_fails() {
+    let _leaks = ::leaks::guard();
+    let (read, write) = unistd::pipe().unwrap();
+    match remove_raw(b"!", &[]) {
+        Err(Error::Protocol(ProtocolError::NoFDReceived(2))) => {}
+        other => { panic!("Expected NoFDReceived, got {:?}", other.is_ok()); }
+    }
+    match remove_raw(b"lots", &[read, write]) {
+        Err(Error::Protocol(ProtocolError::RingFormatError)) => {}
+        other => { panic!("Expected a bad count, got {:?}", other.is_ok()); }
+    }
+    match remove_raw(&[0xff, 0xfe], &[read, write]) {
+        Err(Error::Protocol(ProtocolError::RingFormatError)) => {}
+        other => { panic!("Expected a bad count, got {:?}", other.is_ok()); }
+    }
+    match remove_raw(b"!", &[read, write, read]) {
+        Err(Error::Protocol(ProtocolError::TooManyFDsReceived)) => {}
+        other => { panic!("Expected too many FDs, got {:?}", other.is_ok()); }
+    }
+    unistd::close(read).unwrap();
+    unistd::close(write).unwrap();
+}
+
+#[test]
+fn adding_a_bad_group_fails() {
+    let _leaks = ::leaks::guard();
+    let mut ring = new().unwrap();
+    let empty: &[RawFd] = &[];
+    match ring.add(empty) {
+        Err(Error::Protocol(ProtocolError::BadGroupSize(0))) => {}
+        _ => { panic!("Empty group should not be stashable"); }
+    }
+    assert_eq!(0, ring.count);
+}
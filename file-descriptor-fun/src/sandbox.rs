@@ -0,0 +1,324 @@
+//! Running experiments in a child process with lowered limits.
+//!
+//! Running an FD exhaustion experiment directly in the test process
+//! means running it against the host's real limits (and leaving the
+//! test process with whatever it couldn't clean up).
+//! [`run`](fn.run.html) forks a child, lowers its resource limits,
+//! runs a scenario (see the [`scenario`](../scenario/index.html)
+//! module for some), and sends back a [`Report`](struct.Report.html)
+//! of what happened over a pipe. A child that runs for longer than
+//! its wall-clock limit is killed.
+//!
+//! ```no_run
+//! use filedes::{sandbox, scenario};
+//!
+//! let limits = sandbox::Limits::nofile(64);
+//! match sandbox::run(&limits, scenario::fill_ring).unwrap() {
+//!     sandbox::Outcome::Completed(report) => {
+//!         println!("Stashed {:?} FDs", report.get("stashed"));
+//!     }
+//!     sandbox::Outcome::Died(status) => {
+//!         println!("The child died: {:?}", status);
+//!     }
+//! }
+//! ```
+
+use libc;
+use nix;
+use nix::poll;
+use nix::sys::{signal, wait};
+use nix::unistd;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::panic;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ring;
+
+/// How long a child may run if its limits don't say otherwise.
+pub const DEFAULT_WALL_CLOCK_SECS: u64 = 60;
+
+/// An `RLIMIT_NOFILE` low enough that nothing a child does with its
+/// FDs can hurt the host.
+pub const SAFE_NOFILE: u64 = 64;
+
+/// Resource limits to set in the child before running a scenario.
+/// Both the soft and the hard limit are set, so the scenario can't
+/// raise them again. `None` leaves a limit as it is.
+#[derive(Copy, PartialEq, Eq, Clone, Debug, Default)]
+pub struct Limits {
+    /// `RLIMIT_NOFILE`, the number of FDs the child may have open
+    pub nofile: Option<u64>,
+
+    /// `RLIMIT_AS`, the size of the child's address space in bytes
+    pub address_space: Option<u64>,
+
+    /// `RLIMIT_CPU`, the CPU time the child may use in seconds
+    pub cpu_seconds: Option<u64>,
+
+    /// How long the child may run before it's killed; `None` means
+    /// [`DEFAULT_WALL_CLOCK_SECS`](constant.DEFAULT_WALL_CLOCK_SECS.html)
+    pub wall_clock: Option<Duration>,
+}
+
+impl Limits {
+    /// Limits that only lower `RLIMIT_NOFILE` to `n`.
+    pub fn nofile(n: u64) -> Limits {
+        Limits { nofile: Some(n), ..Limits::default() }
+    }
+}
+
+/// What a scenario found out. Scenarios record named numbers with
+/// [`set`](#method.set); if the scenario returns an error, it's
+/// recorded in [`error`](#structfield.error).
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct Report {
+    /// The numbers the scenario recorded, by name
+    pub metrics: BTreeMap<String, u64>,
+
+    /// A description of the error the scenario returned, if any
+    pub error: Option<String>,
+}
+
+impl Report {
+    /// Records `value` under `name` (which must not contain
+    /// whitespace), replacing any earlier value.
+    pub fn set(&mut self, name: &str, value: u64) {
+        self.metrics.insert(name.to_owned(), value);
+    }
+
+    /// Returns the value recorded under `name`.
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.metrics.get(name).cloned()
+    }
+
+    /// Records the errno of a limit that was hit under `"errno"`.
+    pub fn set_errno(&mut self, err: &nix::Error) {
+        if let nix::Error::Sys(errno) = *err {
+            self.set("errno", errno as i32 as u64);
+        }
+    }
+
+    fn encode(&self) -> String {
+        let mut out = String::new();
+        for (name, value) in self.metrics.iter() {
+            out.push_str(&format!("metric {} {}\n", name, value));
+        }
+        if let Some(ref error) = self.error {
+            out.push_str(&format!("error {}\n", error.replace("\n", " ")));
+        }
+        out
+    }
+
+    fn decode(input: &str) -> Report {
+        let mut report = Report::default();
+        for line in input.lines() {
+            let mut words = line.splitn(2, ' ');
+            match (words.next(), words.next()) {
+                (Some("metric"), Some(rest)) => {
+                    let mut words = rest.split(' ');
+                    if let (Some(name), Some(value)) = (words.next(), words.next()) {
+                        if let Ok(value) = value.parse() {
+                            report.set(name, value);
+                        }
+                    }
+                }
+                (Some("error"), Some(error)) => {
+                    report.error = Some(error.to_owned());
+                }
+                _ => {}
+            }
+        }
+        report
+    }
+}
+
+/// How a sandboxed scenario ended.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Outcome {
+    /// The scenario ran to the end (possibly returning an error, see
+    /// [`Report::error`](struct.Report.html#structfield.error))
+    Completed(Report),
+
+    /// The child died without sending a report (it panicked, was
+    /// killed by a signal, or ran into its CPU or wall-clock limit).
+    /// Contains how it exited.
+    Died(wait::WaitStatus),
+}
+
+fn set_limit(resource: libc::c_int, value: Option<u64>) -> nix::Result<()> {
+    if let Some(value) = value {
+        let limit = libc::rlimit {
+            rlim_cur: value as libc::rlim_t,
+            rlim_max: value as libc::rlim_t,
+        };
+        let res = unsafe { libc::setrlimit(resource as _, &limit) };
+        nix::Errno::result(res)?;
+    }
+    Ok(())
+}
+
+// A pipe whose ends don't leak into children that other threads
+// fork and exec while we're running (a child holding on to our write
+// end would keep us reading forever):
+#[cfg(target_os="linux")]
+fn cloexec_pipe() -> nix::Result<(RawFd, RawFd)> {
+    let mut fds = [-1; 2];
+    let res = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+    nix::Errno::result(res)?;
+    Ok((fds[0], fds[1]))
+}
+
+// Without pipe2, there's a short window in which the FDs can leak:
+#[cfg(not(target_os="linux"))]
+fn cloexec_pipe() -> nix::Result<(RawFd, RawFd)> {
+    unistd::pipe2(nix::fcntl::O_CLOEXEC)
+}
+
+// Runs in the forked child; returns the exit code. Only the forking
+// thread exists in the child, and any lock another thread held when
+// we forked (the allocator's, stdout's) stays locked forever, so do
+// as little as possible before the scenario runs.
+fn child<F>(limits: &Limits, scenario: F, write: RawFd) -> i32
+    where F: FnOnce(&mut Report) -> ring::Result<()>
+{
+    let mut report = Report::default();
+    let limited = set_limit(libc::RLIMIT_NOFILE as libc::c_int, limits.nofile)
+        .and(set_limit(libc::RLIMIT_AS as libc::c_int, limits.address_space))
+        .and(set_limit(libc::RLIMIT_CPU as libc::c_int, limits.cpu_seconds));
+    if let Err(e) = limited {
+        report.set_errno(&e);
+        report.error = Some("Couldn't set limits".to_owned());
+    } else {
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| scenario(&mut report)));
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => { report.error = Some(format!("{:?}", e)); }
+            Err(_) => { return 101; }
+        }
+    }
+    let mut pipe = unsafe { File::from_raw_fd(write) };
+    match pipe.write_all(report.encode().as_bytes()) {
+        Ok(()) => 0,
+        Err(_) => 2,
+    }
+}
+
+/// Runs `scenario` in a forked child process that has the given
+/// `limits`, and returns what happened.
+///
+/// The child exits right after the scenario, without running any
+/// destructors of the parent's state, so whatever the scenario didn't
+/// clean up goes away with the child.
+///
+/// Test harnesses run tests on several threads, and a child forked
+/// while another thread holds a lock can hang; if the child is still
+/// running once its wall-clock limit is up, it's killed, and this
+/// returns `Died`.
+pub fn run<F>(limits: &Limits, scenario: F) -> ring::Result<Outcome>
+    where F: FnOnce(&mut Report) -> ring::Result<()>
+{
+    let wall_clock = limits.wall_clock.unwrap_or(Duration::from_secs(DEFAULT_WALL_CLOCK_SECS));
+    let (read, write) = cloexec_pipe()?;
+    let pid = match unistd::fork() {
+        Ok(unistd::Fork::Child) => {
+            let code = child(limits, scenario, write);
+            unsafe { libc::_exit(code) }
+        }
+        Ok(unistd::Fork::Parent(pid)) => pid,
+        Err(e) => {
+            let _ = unistd::close(read);
+            let _ = unistd::close(write);
+            return Err(ring::Error::from(e));
+        }
+    };
+    let _ = unistd::close(write);
+    let result = watch(pid, read, Instant::now() + wall_clock);
+    let _ = unistd::close(read);
+    let (output, status) = result?;
+    match status {
+        wait::WaitStatus::Exited(_, 0) => {
+            match String::from_utf8(output) {
+                Ok(output) => Ok(Outcome::Completed(Report::decode(&output))),
+                Err(_) => Ok(Outcome::Died(status)),
+            }
+        }
+        status => Ok(Outcome::Died(status)),
+    }
+}
+
+// Reads the child's report from `read` until the child closes its
+// end, then reaps it. If that takes until `deadline`, kills the
+// child instead.
+fn watch(pid: libc::pid_t, read: RawFd, deadline: Instant) -> ring::Result<(Vec<u8>, wait::WaitStatus)> {
+    let mut output = vec![];
+    let mut done_reading = false;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            // It may have exited just now, that's fine:
+            let _ = signal::kill(pid, signal::SIGKILL);
+            return Ok((output, wait::waitpid(pid, None)?));
+        }
+        let left = deadline - now;
+        if done_reading {
+            match wait::waitpid(pid, Some(wait::WNOHANG))? {
+                wait::WaitStatus::StillAlive => {
+                    thread::sleep(cmp::min(left, Duration::from_millis(1)));
+                }
+                status => return Ok((output, status)),
+            }
+            continue;
+        }
+
+        let mut fds = [poll::PollFd { fd: read, events: poll::POLLIN, revents: poll::EventFlags::empty() }];
+        let millis = cmp::min(left.as_secs() * 1000 + left.subsec_nanos() as u64 / 1_000_000 + 1, 1000);
+        match poll::poll(&mut fds, millis as libc::c_int) {
+            Ok(0) | Err(nix::Error::Sys(nix::Errno::EINTR)) => continue,
+            Ok(_) => {}
+            Err(e) => return Err(ring::Error::from(e)),
+        }
+        let mut buf = [0; 4096];
+        match unistd::read(read, &mut buf) {
+            Ok(0) => { done_reading = true; }
+            Ok(n) => { output.extend_from_slice(&buf[..n]); }
+            Err(nix::Error::Sys(nix::Errno::EINTR)) => {}
+            Err(e) => return Err(ring::Error::from(e)),
+        }
+    }
+}
+
+// Unit tests follow:
+
+#[test]
+fn reports_survive_encoding() {
+    let mut report = Report::default();
+    report.set("stashed", 1234);
+    report.set("errno", 24);
+    report.error = Some("Limit(Sys(EMFILE))\nfoo".to_owned());
+    let decoded = Report::decode(&report.encode());
+    assert_eq!(Some(1234), decoded.get("stashed"));
+    assert_eq!(Some(24), decoded.get("errno"));
+    assert_eq!(Some("Limit(Sys(EMFILE)) foo".to_owned()), decoded.error);
+}
+
+#[test]
+fn hanging_children_get_killed() {
+    let _leaks = ::leaks::guard();
+    let limits = Limits { wall_clock: Some(Duration::from_millis(100)), ..Limits::default() };
+    let started = Instant::now();
+    let outcome = run(&limits, |_| {
+        loop {
+            thread::sleep(Duration::from_secs(1));
+        }
+    }).unwrap();
+    match outcome {
+        Outcome::Died(wait::WaitStatus::Signaled(_, signal::SIGKILL, _)) => {}
+        other => { panic!("Expected the child to be killed, got {:?}", other); }
+    }
+    assert!(started.elapsed() < Duration::from_secs(DEFAULT_WALL_CLOCK_SECS));
+}
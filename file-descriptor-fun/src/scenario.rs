@@ -0,0 +1,102 @@
+//! Ready-made experiments to run in a
+//! [`sandbox`](../sandbox/index.html).
+//!
+//! Each scenario records what it managed in a
+//! [`Report`](../sandbox/struct.Report.html): `"stashed"` is the
+//! number of throwaway FDs that ended up in rings, `"errno"` is the
+//! errno of the limit that stopped it, and `"send_buffer"` is the
+//! send buffer size the kernel granted the rings.
+
+use inflight;
+use nix;
+use ring;
+use sandbox::Report;
+use super::add_tmpfile_to_ring;
+
+/// Stashes throwaway files in a single ring until some limit is hit.
+pub fn fill_ring(report: &mut Report) -> ring::Result<()> {
+    let mut ring = ring::new()?;
+    report.set("send_buffer", ring.send_buffer_size()? as u64);
+    loop {
+        match add_tmpfile_to_ring(&mut ring) {
+            Ok(_) => {}
+            Err(ring::Error::Limit(e)) => {
+                report.set_errno(&e);
+                break;
+            }
+            Err(e) => {
+                report.set("stashed", ring.count);
+                return Err(e);
+            }
+        }
+    }
+    report.set("stashed", ring.count);
+    Ok(())
+}
+
+/// Fills rings with throwaway files and stashes each full ring in an
+/// outer ring, until some limit is hit or the outer ring has
+/// `max_outer` entries. Also records `"outer_entries"`.
+pub fn nest_rings(max_outer: u64, report: &mut Report) -> ring::Result<()> {
+    let mut outer_ring = ring::new()?;
+    report.set("send_buffer", outer_ring.send_buffer_size()? as u64);
+    let mut total = 0;
+    while outer_ring.count < max_outer {
+        let mut inner_ring = match ring::new() {
+            Ok(ring) => ring,
+            Err(ring::Error::Limit(e)) => {
+                report.set_errno(&e);
+                break;
+            }
+            Err(e) => { return Err(e); }
+        };
+        let mut done = false;
+        loop {
+            match add_tmpfile_to_ring(&mut inner_ring) {
+                Ok(n) => { total += n; }
+                Err(ring::Error::Limit(nix::Error::Sys(nix::Errno::EAGAIN))) if inner_ring.count > 0 => {
+                    // This one's full, on to the next:
+                    break;
+                }
+                Err(ring::Error::Limit(e)) => {
+                    report.set_errno(&e);
+                    done = true;
+                    break;
+                }
+                Err(e) => { return Err(e); }
+            }
+        }
+        if inner_ring.count > 0 {
+            match outer_ring.add(&inner_ring) {
+                Ok(()) => {}
+                Err(ring::Error::Limit(e)) => {
+                    // This inner ring is lost, adjust totals for it:
+                    total -= inner_ring.count;
+                    report.set_errno(&e);
+                    done = true;
+                }
+                Err(e) => { return Err(e); }
+            }
+        }
+        if done {
+            break;
+        }
+    }
+    report.set("outer_entries", outer_ring.count);
+    report.set("stashed", total);
+    Ok(())
+}
+
+/// Measures how many FDs can be in flight across up to `max_rings`
+/// rings (see [`inflight::measure`](../inflight/fn.measure.html)).
+/// Records `"in_flight"`, `"rings"` and `"nofile_soft"`.
+pub fn in_flight(max_rings: u64, report: &mut Report) -> ring::Result<()> {
+    let measured = inflight::measure(max_rings)?;
+    report.set("in_flight", measured.in_flight);
+    report.set("rings", measured.rings);
+    report.set("nofile_soft", measured.limits.soft);
+    if let Some(e) = measured.stopped_by {
+        report.set_errno(&e);
+    }
+    Ok(())
+}
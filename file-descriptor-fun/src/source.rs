@@ -0,0 +1,311 @@
+//! Different kinds of throwaway file descriptors to stash.
+//!
+//! [`add_tmpfile_to_ring`](../fn.add_tmpfile_to_ring.html) and
+//! [`add_two_sockets_to_ring`](../fn.add_two_sockets_to_ring.html)
+//! only ever stash files and socket pairs. An
+//! [`FdSource`](trait.FdSource.html) makes some other kind of FD
+//! (pipes, eventfds, epoll instances, ...), so experiments can compare
+//! how the kernel treats each of them once they're stashed:
+//!
+//! ```no_run
+//! use filedes::{ring, source};
+//!
+//! let mut ring = ring::new().unwrap();
+//! for source in source::all() {
+//!     let stored = source::add_to_ring(&mut ring, &*source).unwrap();
+//!     println!("{}: stashed {:?}", source.name(), stored);
+//! }
+//! ```
+//!
+//! Most of these are Linux only; [`all`](fn.all.html) returns the
+//! ones that exist on the current OS.
+
+use nix;
+use nix::unistd;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{IntoRawFd, RawFd};
+
+use ring;
+use watchdog;
+
+/// Something that creates throwaway file descriptors.
+pub trait FdSource {
+    /// A short name for the kind of FD, for reports.
+    fn name(&self) -> &'static str;
+
+    /// Creates new FDs (most sources make one, some make a connected
+    /// pair). The caller owns them and has to close them.
+    fn open(&self) -> ring::Result<Vec<RawFd>>;
+}
+
+/// Anonymous files created with `memfd_create`.
+#[cfg(target_os="linux")]
+#[derive(Copy, Clone, Debug)]
+pub struct MemFd;
+
+#[cfg(target_os="linux")]
+impl FdSource for MemFd {
+    fn name(&self) -> &'static str { "memfd" }
+
+    fn open(&self) -> ring::Result<Vec<RawFd>> {
+        Ok(vec![super::memfd()?])
+    }
+}
+
+/// Temporary files created with `mkstemp` and unlinked right away.
+#[derive(Copy, Clone, Debug)]
+pub struct TempFile;
+
+impl FdSource for TempFile {
+    fn name(&self) -> &'static str { "mkstemp" }
+
+    fn open(&self) -> ring::Result<Vec<RawFd>> {
+        Ok(vec![super::tempfile()?])
+    }
+}
+
+/// Both ends of a pipe.
+#[derive(Copy, Clone, Debug)]
+pub struct Pipe;
+
+impl FdSource for Pipe {
+    fn name(&self) -> &'static str { "pipe" }
+
+    fn open(&self) -> ring::Result<Vec<RawFd>> {
+        let (read, write) = unistd::pipe()?;
+        Ok(vec![read, write])
+    }
+}
+
+/// Event counters created with `eventfd`.
+#[cfg(target_os="linux")]
+#[derive(Copy, Clone, Debug)]
+pub struct EventFd;
+
+#[cfg(target_os="linux")]
+impl FdSource for EventFd {
+    fn name(&self) -> &'static str { "eventfd" }
+
+    fn open(&self) -> ring::Result<Vec<RawFd>> {
+        use nix::sys::eventfd;
+
+        Ok(vec![eventfd::eventfd(0, eventfd::EventFdFlag::empty())?])
+    }
+}
+
+// nix doesn't wrap timerfd and inotify yet (and its signalfd module
+// doesn't build):
+#[cfg(target_os="linux")]
+mod ffi {
+    use libc::{c_int, sigset_t};
+
+    extern "C" {
+        pub fn timerfd_create(clockid: c_int, flags: c_int) -> c_int;
+        pub fn inotify_init1(flags: c_int) -> c_int;
+        pub fn signalfd(fd: c_int, mask: *const sigset_t, flags: c_int) -> c_int;
+    }
+}
+
+/// Timers created with `timerfd_create` (never armed).
+#[cfg(target_os="linux")]
+#[derive(Copy, Clone, Debug)]
+pub struct TimerFd;
+
+#[cfg(target_os="linux")]
+impl FdSource for TimerFd {
+    fn name(&self) -> &'static str { "timerfd" }
+
+    fn open(&self) -> ring::Result<Vec<RawFd>> {
+        use libc;
+
+        let fd = unsafe { ffi::timerfd_create(libc::CLOCK_MONOTONIC, 0) };
+        Ok(vec![nix::Errno::result(fd)?])
+    }
+}
+
+/// `signalfd`s listening for `SIGUSR1` (which isn't blocked, so they
+/// never get to see it).
+#[cfg(target_os="linux")]
+#[derive(Copy, Clone, Debug)]
+pub struct SignalFd;
+
+#[cfg(target_os="linux")]
+impl FdSource for SignalFd {
+    fn name(&self) -> &'static str { "signalfd" }
+
+    fn open(&self) -> ring::Result<Vec<RawFd>> {
+        use libc;
+        use std::mem;
+
+        let fd = unsafe {
+            let mut mask: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut mask);
+            libc::sigaddset(&mut mask, libc::SIGUSR1);
+            ffi::signalfd(-1, &mask, 0)
+        };
+        Ok(vec![nix::Errno::result(fd)?])
+    }
+}
+
+/// Empty epoll instances.
+#[cfg(target_os="linux")]
+#[derive(Copy, Clone, Debug)]
+pub struct Epoll;
+
+#[cfg(target_os="linux")]
+impl FdSource for Epoll {
+    fn name(&self) -> &'static str { "epoll" }
+
+    fn open(&self) -> ring::Result<Vec<RawFd>> {
+        use nix::sys::epoll;
+
+        Ok(vec![epoll::epoll_create()?])
+    }
+}
+
+/// inotify instances without any watches.
+#[cfg(target_os="linux")]
+#[derive(Copy, Clone, Debug)]
+pub struct Inotify;
+
+#[cfg(target_os="linux")]
+impl FdSource for Inotify {
+    fn name(&self) -> &'static str { "inotify" }
+
+    fn open(&self) -> ring::Result<Vec<RawFd>> {
+        let fd = unsafe { ffi::inotify_init1(0) };
+        Ok(vec![nix::Errno::result(fd)?])
+    }
+}
+
+// (internal) Turns an io::Error from std's sockets into a ring error,
+// so that e.g. EMFILE still counts as hitting a limit.
+fn from_io(err: io::Error) -> ring::Error {
+    let errno = nix::Errno::from_i32(err.raw_os_error().unwrap_or(0));
+    ring::Error::from(nix::Error::Sys(errno))
+}
+
+/// Both ends of a TCP connection over the loopback interface (the
+/// listening socket is closed again).
+#[derive(Copy, Clone, Debug)]
+pub struct TcpLoopback;
+
+impl FdSource for TcpLoopback {
+    fn name(&self) -> &'static str { "tcp" }
+
+    fn open(&self) -> ring::Result<Vec<RawFd>> {
+        let listener = TcpListener::bind("127.0.0.1:0").map_err(from_io)?;
+        let addr = listener.local_addr().map_err(from_io)?;
+        let client = TcpStream::connect(addr).map_err(from_io)?;
+        let (server, _) = listener.accept().map_err(from_io)?;
+        Ok(vec![client.into_raw_fd(), server.into_raw_fd()])
+    }
+}
+
+/// Both ends of a UNIX domain socket pair (what
+/// [`add_two_sockets_to_ring`](../fn.add_two_sockets_to_ring.html)
+/// stashes).
+#[derive(Copy, Clone, Debug)]
+pub struct SocketPair;
+
+impl FdSource for SocketPair {
+    fn name(&self) -> &'static str { "socketpair" }
+
+    fn open(&self) -> ring::Result<Vec<RawFd>> {
+        let (one, two) = super::unix_socket_pair()?;
+        Ok(vec![one, two])
+    }
+}
+
+/// Returns one of each source that works on this OS.
+pub fn all() -> Vec<Box<dyn FdSource>> {
+    let mut sources: Vec<Box<dyn FdSource>> = vec![];
+    if cfg!(target_os="linux") {
+        sources.extend(linux_only());
+    }
+    sources.push(Box::new(TempFile));
+    sources.push(Box::new(Pipe));
+    sources.push(Box::new(TcpLoopback));
+    sources.push(Box::new(SocketPair));
+    sources
+}
+
+#[cfg(target_os="linux")]
+fn linux_only() -> Vec<Box<dyn FdSource>> {
+    vec![Box::new(MemFd), Box::new(EventFd), Box::new(TimerFd),
+         Box::new(SignalFd), Box::new(Epoll), Box::new(Inotify)]
+}
+
+#[cfg(not(target_os="linux"))]
+fn linux_only() -> Vec<Box<dyn FdSource>> {
+    vec![]
+}
+
+/// Creates FDs with `source`, adds them to the ring as a single
+/// entry (see [`Ring::add_together`](../ring/struct.Ring.html#method.add_together)),
+/// closes them, and returns what was stored.
+///
+/// Either all the FDs end up in the ring, or none do; they get closed
+/// either way.
+///
+/// If a [`watchdog`](../watchdog/index.html) is armed and trips, this
+/// returns an error without creating any FDs.
+pub fn add_to_ring(ring: &mut ring::Ring, source: &dyn FdSource) -> ring::Result<ring::Stored> {
+    watchdog::check()?;
+    let fds = source.open()?;
+    let stored = ring.add_together(&fds);
+    let closed = ring::close_all(&fds);
+    let stored = stored?;
+    closed?;
+    Ok(stored)
+}
+
+// Unit tests follow:
+
+// What kind of thing a description is about, ignoring inode numbers
+// and paths (which differ between FDs of the same kind):
+#[cfg(test)]
+fn kind_name(description: &::kind::Description) -> String {
+    use kind::Description;
+
+    match *description {
+        Description::MemFd(_) => "memfd".to_owned(),
+        Description::File(_) => "file".to_owned(),
+        Description::Pipe(_) => "pipe".to_owned(),
+        Description::Socket(kind, _) => format!("{:?}", kind),
+        Description::EventFd(_) => "eventfd".to_owned(),
+        ref other => format!("{}", other),
+    }
+}
+
+#[test]
+fn every_source_can_be_stashed_and_popped() {
+    use kind;
+    use ring::StashedThing;
+
+    let _leaks = ::leaks::guard();
+    for source in all() {
+        let mut ring = ring::new().unwrap();
+        let expected: Vec<String> = source.open().unwrap().into_iter().map(|fd| {
+            let description = kind::describe(fd).unwrap();
+            unistd::close(fd).unwrap();
+            kind_name(&description)
+        }).collect();
+        let stored = add_to_ring(&mut ring, &*source).unwrap();
+        assert_eq!(expected.len() as u64, stored.fds());
+        assert_eq!(1, ring.count);
+
+        let got: Vec<String> = match ring.pop().unwrap() {
+            StashedThing::One(fd) => vec![fd],
+            StashedThing::Group(fds) => fds,
+            StashedThing::Pair(_) => { panic!("Expected FDs from {}, not a ring", source.name()); }
+        }.into_iter().map(|fd| {
+            let description = kind::describe(fd).unwrap();
+            unistd::close(fd).unwrap();
+            kind_name(&description)
+        }).collect();
+        assert_eq!(expected, got, "{} came back different", source.name());
+        assert_eq!(0, ring.count);
+    }
+}
@@ -0,0 +1,51 @@
+//! Reading kernel parameters from `/proc/sys` (so, Linux only).
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::PathBuf;
+
+/// Returns the path under `/proc/sys` for a dotted sysctl name like
+/// `"fs.file-max"`.
+pub fn path(name: &str) -> PathBuf {
+    PathBuf::from("/proc/sys").join(name.replace(".", "/"))
+}
+
+/// Reads the sysctl `name` (e.g. `"fs.file-nr"`) and returns all the
+/// whitespace-separated numbers in it.
+pub fn read(name: &str) -> io::Result<Vec<u64>> {
+    let mut contents = String::new();
+    let mut file = File::open(path(name))?;
+    file.read_to_string(&mut contents)?;
+
+    let mut values = vec![];
+    for word in contents.split_whitespace() {
+        match word.parse() {
+            Ok(n) => values.push(n),
+            Err(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("{} contains non-number {:?}", name, word)));
+            }
+        }
+    }
+    Ok(values)
+}
+
+/// Reads a sysctl that consists of a single number.
+pub fn read_one(name: &str) -> io::Result<u64> {
+    let values = read(name)?;
+    match values.first() {
+        Some(&n) => Ok(n),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is empty", name))),
+    }
+}
+
+// Unit tests follow:
+
+#[cfg(target_os="linux")]
+#[test]
+fn it_reads_file_max() {
+    let _leaks = ::leaks::guard();
+    assert!(read_one("fs.file-max").unwrap() > 0);
+    assert_eq!(3, read("fs.file-nr").unwrap().len());
+}
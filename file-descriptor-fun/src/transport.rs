@@ -0,0 +1,514 @@
+//! How a [`Ring`](../ring/struct.Ring.html) moves its entries around.
+//!
+//! A ring entry is a message: a few bytes of payload plus some FDs.
+//! A [`Transport`](trait.Transport.html) sends such messages in at a
+//! ring's write end and receives them at its read end. Normal rings
+//! use [`SOCKETS`](static.SOCKETS.html), which sends them through a
+//! UNIX domain socket pair with `sendmsg` / `recvmsg`.
+//!
+//! Rings made with [`ring::in_memory`](../ring/fn.in_memory.html)
+//! use [`MEMORY`](static.MEMORY.html) instead, which keeps the
+//! messages in a queue in this process. That way, the ring protocol
+//! (ordering, counting, rings in rings) can be tested without the
+//! kernel's buffer sizes and limits getting in the way.
+//!
+//! A ring can be stashed in a ring of the other kind, with one
+//! exception: the kernel doesn't tell us when it drops the copies of
+//! an in-memory ring it has in flight, so in-memory rings can't go
+//! into socket rings.
+
+use nix;
+use nix::sys::socket;
+use nix::sys::stat;
+use nix::sys::uio::IoVec;
+use nix::unistd;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::io::RawFd;
+
+use faults;
+use faults::Syscall;
+use kind;
+use kind::FdKind;
+use ring;
+use ring::MAX_FDS_PER_ENTRY;
+
+/// Sends and receives ring entries.
+pub trait Transport: Sync {
+    /// Sends `payload` and `fds` as one message in at the `write` end
+    /// of a ring. The caller keeps ownership of `fds`.
+    fn send(&self, write: RawFd, payload: &[u8], fds: &[RawFd]) -> ring::Result<()>;
+
+    /// Receives the oldest message at the `read` end of a ring,
+    /// putting as much of its payload as fits into `buf`. The caller
+    /// owns the FDs that came with it.
+    ///
+    /// Fails with [`Limit(EAGAIN)`](../ring/enum.Error.html#variant.Limit)
+    /// if there's no message.
+    fn recv(&self, read: RawFd, buf: &mut [u8]) -> ring::Result<Received>;
+
+    /// Closes both ends of a ring (and, for transports that keep
+    /// their own messages, drops any that can't be received anymore).
+    fn close(&self, read: RawFd, write: RawFd) -> ring::Result<()>;
+
+    /// Whether the messages stay in this process instead of going
+    /// through the kernel.
+    fn in_process(&self) -> bool;
+}
+
+/// Returns the transport of the ring whose read or write end `fd` is:
+/// [`SOCKETS`](static.SOCKETS.html) for sockets, and
+/// [`MEMORY`](static.MEMORY.html) for the pipes of in-memory rings.
+/// Fails with `Bad(EBADF)` for anything else.
+pub fn of(fd: RawFd) -> ring::Result<&'static dyn Transport> {
+    if let FdKind::Socket(_) = kind::kind_of(fd)? {
+        return Ok(&SOCKETS);
+    }
+    let ino = inode(fd)?;
+    if QUEUES.with(|queues| queues.borrow().contains_key(&ino)) {
+        Ok(&MEMORY)
+    } else {
+        Err(not_a_ring())
+    }
+}
+
+/// A message that came out of a ring, ready for
+/// [`wire::decode`](../wire/fn.decode.html).
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Received {
+    /// How many bytes of payload were put into the buffer
+    pub bytes: usize,
+
+    /// The FDs that came with the message
+    pub fds: Vec<RawFd>,
+
+    /// The flags `recvmsg` returned (`MSG_TRUNC` and `MSG_CTRUNC` are
+    /// the interesting ones)
+    pub flags: socket::MsgFlags,
+}
+
+/// Sends entries through a UNIX domain socket pair.
+pub struct Sockets;
+
+/// The transport of normal rings.
+pub static SOCKETS: Sockets = Sockets;
+
+impl Transport for Sockets {
+    fn send(&self, write: RawFd, payload: &[u8], fds: &[RawFd]) -> ring::Result<()> {
+        let buf = [IoVec::from_slice(payload)];
+        let cmsgs = [socket::ControlMessage::ScmRights(fds)];
+        faults::check(Syscall::SendMsg)?;
+        socket::sendmsg(write, &buf, &cmsgs, socket::MsgFlags::empty(), None)?;
+        Ok(())
+    }
+
+    fn recv(&self, read: RawFd, buf: &mut [u8]) -> ring::Result<Received> {
+        let mut cmsg: socket::CmsgSpace<[RawFd; MAX_FDS_PER_ENTRY]> = socket::CmsgSpace::new();
+        let mut iovs = [IoVec::from_mut_slice(buf)];
+        faults::check(Syscall::RecvMsg)?;
+        let msg = socket::recvmsg(read, &mut iovs, Some(&mut cmsg), socket::MsgFlags::empty())?;
+        // Other kinds of control messages carry no FDs, so there's
+        // nothing to clean up if a peer sends them:
+        let mut fds = vec![];
+        for cmsg in msg.cmsgs() {
+            if let socket::ControlMessage::ScmRights(received) = cmsg {
+                fds.extend_from_slice(received);
+            }
+        }
+        Ok(Received { bytes: msg.bytes, fds: fds, flags: msg.flags })
+    }
+
+    fn close(&self, read: RawFd, write: RawFd) -> ring::Result<()> {
+        unistd::close(write)?;
+        unistd::close(read)?;
+        Ok(())
+    }
+
+    fn in_process(&self) -> bool {
+        false
+    }
+}
+
+/// Keeps entries in a queue in this process.
+///
+/// Each in-memory ring is a pipe that never carries any data; its
+/// inode identifies the ring's queue, so a ring that was stashed in
+/// another ring (and comes back out with different FD numbers) still
+/// finds its entries. FDs in the queue are `dup`s of the ones that
+/// were sent, like the copies the kernel keeps in flight.
+///
+/// The queues live in a thread-local table, so in-memory rings must
+/// stay on the thread that created them. A queue is dropped when the
+/// last ring (or entry in another queue) referring to it is; if you
+/// pop an in-memory ring's FDs as anything other than a
+/// [`Pair`](../ring/enum.StashedThing.html#variant.Pair), its queue
+/// stays around.
+pub struct Memory;
+
+/// The transport of [`in_memory`](../ring/fn.in_memory.html) rings.
+pub static MEMORY: Memory = Memory;
+
+struct Message {
+    payload: Vec<u8>,
+    fds: Vec<RawFd>,
+}
+
+struct Queue {
+    messages: VecDeque<Message>,
+    capacity: usize,
+
+    // How many of our FDs (in Rings or in other queues) refer to this
+    // queue's pipe; the queue goes away when the last one is closed.
+    refs: u64,
+}
+
+// Queues by the device and inode of their pipe:
+thread_local!(static QUEUES: RefCell<HashMap<(u64, u64), Queue>> = RefCell::new(HashMap::new()));
+
+fn inode(fd: RawFd) -> ring::Result<(u64, u64)> {
+    faults::check(Syscall::Fstat)?;
+    let st = stat::fstat(fd)?;
+    Ok((st.st_dev as u64, st.st_ino as u64))
+}
+
+fn not_a_ring() -> ring::Error {
+    ring::Error::Bad(nix::Error::Sys(nix::Errno::EBADF))
+}
+
+impl Memory {
+    /// Creates the read and write end of a new in-memory ring that
+    /// holds at most `capacity` entries.
+    pub fn open(&self, capacity: usize) -> ring::Result<(RawFd, RawFd)> {
+        let (read, write) = unistd::pipe()?;
+        let ino = inode(read)?;
+        QUEUES.with(|queues| {
+            queues.borrow_mut().insert(ino, Queue {
+                messages: VecDeque::new(),
+                capacity: capacity,
+                refs: 2,
+            });
+        });
+        Ok((read, write))
+    }
+
+    /// Returns how many entries the in-memory ring whose end `fd` is
+    /// holds.
+    pub fn queued(&self, fd: RawFd) -> ring::Result<usize> {
+        let ino = inode(fd)?;
+        QUEUES.with(|queues| {
+            queues.borrow().get(&ino).map(|queue| queue.messages.len()).ok_or_else(not_a_ring)
+        })
+    }
+
+    // (internal) Counts another reference to the queue for `fd`, if
+    // `fd` is an in-memory ring's pipe.
+    fn retain(&self, fd: RawFd) -> ring::Result<()> {
+        let ino = inode(fd)?;
+        QUEUES.with(|queues| {
+            if let Some(queue) = queues.borrow_mut().get_mut(&ino) {
+                queue.refs += 1;
+            }
+        });
+        Ok(())
+    }
+
+    // (internal) Closes `fd`, dropping the queue it refers to (and
+    // everything in it) if that was the last reference.
+    fn release(&self, fd: RawFd) -> ring::Result<()> {
+        let ino = inode(fd)?;
+        unistd::close(fd)?;
+        let dropped = QUEUES.with(|queues| {
+            let mut queues = queues.borrow_mut();
+            let last = match queues.get_mut(&ino) {
+                Some(queue) => {
+                    queue.refs -= 1;
+                    queue.refs == 0
+                }
+                None => false,
+            };
+            if last { queues.remove(&ino) } else { None }
+        });
+        if let Some(queue) = dropped {
+            for message in queue.messages {
+                for fd in message.fds {
+                    self.release(fd)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transport for Memory {
+    fn send(&self, write: RawFd, payload: &[u8], fds: &[RawFd]) -> ring::Result<()> {
+        let ino = inode(write)?;
+        let full = QUEUES.with(|queues| {
+            queues.borrow().get(&ino).map(|queue| queue.messages.len() >= queue.capacity).ok_or_else(not_a_ring)
+        })?;
+        if full {
+            return Err(ring::Error::Limit(nix::Error::Sys(nix::Errno::EAGAIN)));
+        }
+
+        let mut copies = vec![];
+        for &fd in fds {
+            match unistd::dup(fd) {
+                Ok(copy) => copies.push(copy),
+                Err(e) => {
+                    for copy in copies {
+                        unistd::close(copy)?;
+                    }
+                    return Err(ring::Error::from(e));
+                }
+            }
+        }
+        for (i, &copy) in copies.iter().enumerate() {
+            if let Err(e) = self.retain(copy) {
+                // Put back the references taken so far, and close
+                // every copy:
+                for &retained in copies[..i].iter() {
+                    let _ = self.release(retained);
+                }
+                let _ = ring::close_all(&copies[i..]);
+                return Err(e);
+            }
+        }
+        QUEUES.with(|queues| {
+            if let Some(queue) = queues.borrow_mut().get_mut(&ino) {
+                queue.messages.push_back(Message {
+                    payload: payload.to_vec(),
+                    fds: copies,
+                });
+            }
+        });
+        Ok(())
+    }
+
+    fn recv(&self, read: RawFd, buf: &mut [u8]) -> ring::Result<Received> {
+        let ino = inode(read)?;
+        let message = QUEUES.with(|queues| {
+            match queues.borrow_mut().get_mut(&ino) {
+                Some(queue) => queue.messages.pop_front().ok_or(ring::Error::Limit(nix::Error::Sys(nix::Errno::EAGAIN))),
+                None => Err(not_a_ring()),
+            }
+        })?;
+        let (len, flags) = if message.payload.len() <= buf.len() {
+            (message.payload.len(), socket::MsgFlags::empty())
+        } else {
+            (buf.len(), socket::MSG_TRUNC)
+        };
+        buf[..len].copy_from_slice(&message.payload[..len]);
+        Ok(Received { bytes: len, fds: message.fds, flags: flags })
+    }
+
+    fn close(&self, read: RawFd, write: RawFd) -> ring::Result<()> {
+        self.release(write)?;
+        self.release(read)?;
+        Ok(())
+    }
+
+    fn in_process(&self) -> bool {
+        true
+    }
+}
+
+// Unit tests follow:
+
+#[cfg(test)]
+fn open_queues() -> usize {
+    QUEUES.with(|queues| queues.borrow().len())
+}
+
+#[test]
+fn memory_rings_keep_order_and_count() {
+    use ring::StashedThing;
+
+    let _leaks = ::leaks::guard();
+    let mut ring = ring::in_memory(3).unwrap();
+    let (read, write) = unistd::pipe().unwrap();
+    ring.add(read).unwrap();
+    ring.add(&[read, write][..]).unwrap();
+    ring.add(write).unwrap();
+    match ring.add(read) {
+        Err(ring::Error::Limit(nix::Error::Sys(nix::Errno::EAGAIN))) => {}
+        other => { panic!("Expected the ring to be full, got {:?}", other); }
+    }
+    assert_eq!(3, ring.count);
+
+    let kinds: Vec<usize> = (0..3).map(|_| {
+        match ring.pop().unwrap() {
+            StashedThing::One(fd) => { unistd::close(fd).unwrap(); 1 }
+            StashedThing::Group(fds) => {
+                for &fd in fds.iter() {
+                    unistd::close(fd).unwrap();
+                }
+                fds.len()
+            }
+            StashedThing::Pair(_) => { panic!("No rings in here"); }
+        }
+    }).collect();
+    assert_eq!(vec![1, 2, 1], kinds);
+    assert_eq!(0, ring.count);
+    unistd::close(read).unwrap();
+    unistd::close(write).unwrap();
+}
+
+#[test]
+fn memory_rings_nest() {
+    use ring::StashedThing;
+
+    let _leaks = ::leaks::guard();
+    let mut outer = ring::in_memory(10).unwrap();
+    {
+        let mut inner = ring::in_memory(10).unwrap();
+        let (read, write) = unistd::pipe().unwrap();
+        inner.add(read).unwrap();
+        inner.add(write).unwrap();
+        unistd::close(read).unwrap();
+        unistd::close(write).unwrap();
+        outer.add(&inner).unwrap();
+    }
+    // The inner ring lives on in the outer one:
+    assert_eq!(2, open_queues());
+
+    match outer.pop().unwrap() {
+        StashedThing::Pair(mut inner) => {
+            assert_eq!(2, inner.count);
+            while inner.count > 0 {
+                match inner.pop().unwrap() {
+                    StashedThing::One(fd) => { unistd::close(fd).unwrap(); }
+                    _ => { panic!("Expected single FDs"); }
+                }
+            }
+        }
+        _ => { panic!("Expected a ring"); }
+    }
+    assert_eq!(1, open_queues());
+    drop(outer);
+    assert_eq!(0, open_queues());
+}
+
+#[test]
+fn failing_to_retain_a_nested_ring_releases_it() {
+    let _leaks = ::leaks::guard();
+    let mut outer = ring::in_memory(10).unwrap();
+    {
+        let mut inner = ring::in_memory(10).unwrap();
+        let (read, write) = unistd::pipe().unwrap();
+        inner.add(read).unwrap();
+        unistd::close(read).unwrap();
+        unistd::close(write).unwrap();
+        // Finding the queue for the inner ring's write end fails:
+        let _injected = faults::inject(Syscall::Fstat, 3, nix::Errno::EIO);
+        match outer.add(&inner) {
+            Err(ring::Error::Bad(nix::Error::Sys(nix::Errno::EIO))) => {}
+            other => { panic!("Expected EIO, got {:?}", other); }
+        }
+    }
+    assert_eq!(0, outer.count);
+    // Dropping the inner ring dropped its queue, and the pipe in it:
+    assert_eq!(1, open_queues());
+}
+
+#[test]
+fn dropping_memory_rings_drops_their_contents() {
+    let _leaks = ::leaks::guard();
+    {
+        let mut outer = ring::in_memory(10).unwrap();
+        for _ in 0..3 {
+            let inner = ring::in_memory(10).unwrap();
+            outer.add(&inner).unwrap();
+        }
+        assert_eq!(4, open_queues());
+    }
+    assert_eq!(0, open_queues());
+}
+
+// Adds and pops entries in a pseudo-random order, and checks that
+// the ring behaves like a bounded queue.
+#[test]
+fn memory_rings_behave_like_queues() {
+    use ring::StashedThing;
+    use std::collections::VecDeque;
+
+    let _leaks = ::leaks::guard();
+    const CAPACITY: usize = 16;
+    let mut ring = ring::in_memory(CAPACITY).unwrap();
+    let mut model: VecDeque<usize> = VecDeque::new();
+    let (read, write) = unistd::pipe().unwrap();
+    let mut state: u32 = 12345;
+    for step in 0..2000 {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        let roll = (state >> 16) % 100;
+        if roll < 55 {
+            // Groups of 1 to 3 FDs, so each entry can be told apart:
+            let size = (step % 3) + 1;
+            let group = vec![read; size];
+            match ring.add(&group[..]) {
+                Ok(()) => { model.push_back(size); }
+                Err(ring::Error::Limit(nix::Error::Sys(nix::Errno::EAGAIN))) => {
+                    assert_eq!(CAPACITY, model.len());
+                }
+                Err(e) => { panic!("Unexpected {:?}", e); }
+            }
+        } else {
+            match ring.pop() {
+                Ok(StashedThing::Group(fds)) => {
+                    assert_eq!(model.pop_front(), Some(fds.len()));
+                    for fd in fds {
+                        unistd::close(fd).unwrap();
+                    }
+                }
+                Ok(_) => { panic!("Expected a group"); }
+                Err(ring::Error::Limit(nix::Error::Sys(nix::Errno::EAGAIN))) => {
+                    assert!(model.is_empty());
+                }
+                Err(e) => { panic!("Unexpected {:?}", e); }
+            }
+        }
+        assert_eq!(model.len() as u64, ring.count);
+    }
+    unistd::close(read).unwrap();
+    unistd::close(write).unwrap();
+}
+
+#[test]
+fn socket_rings_nest_in_memory_rings() {
+    use ring::StashedThing;
+
+    let _leaks = ::leaks::guard();
+    let mut outer = ring::in_memory(10).unwrap();
+    {
+        let mut inner = ring::new().unwrap();
+        let (read, write) = unistd::pipe().unwrap();
+        inner.add(read).unwrap();
+        unistd::close(read).unwrap();
+        unistd::close(write).unwrap();
+        outer.add(&inner).unwrap();
+    }
+    match outer.pop().unwrap() {
+        StashedThing::Pair(mut inner) => {
+            match inner.pop().unwrap() {
+                StashedThing::One(fd) => { unistd::close(fd).unwrap(); }
+                _ => { panic!("Expected a single FD"); }
+            }
+        }
+        _ => { panic!("Expected a ring"); }
+    }
+    drop(outer);
+    assert_eq!(0, open_queues());
+}
+
+#[test]
+fn memory_rings_dont_go_into_socket_rings() {
+    let _leaks = ::leaks::guard();
+    let mut outer = ring::new().unwrap();
+    {
+        let inner = ring::in_memory(10).unwrap();
+        match outer.add(&inner) {
+            Err(ring::Error::Bad(nix::Error::Sys(nix::Errno::EINVAL))) => {}
+            other => { panic!("Expected EINVAL, got {:?}", other); }
+        }
+    }
+    assert_eq!(0, outer.count);
+    assert_eq!(0, open_queues());
+}
@@ -0,0 +1,221 @@
+//! A ring that stashes its full rings in more rings.
+//!
+//! A single [`Ring`](../ring/struct.Ring.html) only holds as many
+//! entries as fit in its socket's send buffer. A
+//! [`RingTree`](struct.RingTree.html) has the same `add` / `pop`
+//! interface, but when the ring it's adding to is full, it stashes
+//! that ring in a parent ring and starts a fresh one. Parent rings
+//! that have `fan_out` rings in them get stashed in their own parent,
+//! and so on, up to `max_depth` levels (see
+//! [`nesting::probe`](../nesting/fn.probe.html) for how deep your
+//! kernel lets you go).
+//!
+//! (This does not work on OS X, since it can't stash rings.)
+
+use nix;
+use std::fmt;
+use std::mem;
+
+use ring;
+use ring::{Ring, StashableThing, StashedThing};
+
+/// A tree of rings, behaving like one big [`Ring`](../ring/struct.Ring.html).
+///
+/// Entries come out of [`pop`](#method.pop) in the order they went
+/// into [`add`](#method.add).
+pub struct RingTree {
+    // The ring that new entries go into:
+    leaf: Ring,
+
+    // The oldest full leaf, taken out of `spilled` to pop entries from:
+    head: Option<Ring>,
+
+    // Full leaves, oldest first:
+    spilled: Option<Box<RingTree>>,
+
+    leaf_capacity: u64,
+    fan_out: u64,
+    max_depth: u64,
+
+    /// The number of entries in the tree
+    pub count: u64,
+}
+
+impl fmt::Display for RingTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#<RingTree containing {} entries in {} levels>", self.count, self.depth() + 1)
+    }
+}
+
+/// Creates a new RingTree whose leaves hold as much as their socket
+/// buffers can take.
+pub fn new(fan_out: u64, max_depth: u64) -> ring::Result<RingTree> {
+    with_leaf_capacity(u64::max_value(), fan_out, max_depth)
+}
+
+/// Creates a new RingTree whose leaves hold at most `leaf_capacity`
+/// entries, whose other rings hold at most `fan_out` rings, and which
+/// nests rings at most `max_depth` levels deep.
+pub fn with_leaf_capacity(leaf_capacity: u64, fan_out: u64, max_depth: u64) -> ring::Result<RingTree> {
+    Ok(RingTree {
+        leaf: ring::new()?,
+        head: None,
+        spilled: None,
+        leaf_capacity: if leaf_capacity == 0 { 1 } else { leaf_capacity },
+        fan_out: if fan_out == 0 { 1 } else { fan_out },
+        max_depth: max_depth,
+        count: 0,
+    })
+}
+
+fn full() -> ring::Error {
+    ring::Error::Limit(nix::Error::Sys(nix::Errno::EAGAIN))
+}
+
+impl<'a> RingTree {
+    /// Returns how many levels of rings are currently stashed in
+    /// rings.
+    pub fn depth(&self) -> u64 {
+        match self.spilled {
+            Some(ref spilled) => spilled.depth() + 1,
+            None => 0,
+        }
+    }
+
+    /// Adds an entry to the tree, stashing the current ring and
+    /// starting a new one if it's full.
+    ///
+    /// # Errors
+    /// The same as [`Ring::add`](../ring/struct.Ring.html#method.add);
+    /// if the tree is full (its rings are `max_depth` levels deep and
+    /// the top one is full, too), this returns
+    /// [`Limit(EAGAIN)`](../ring/enum.Error.html#variant.Limit).
+    pub fn add<T: Into<StashableThing<'a>>>(&mut self, thing: T) -> ring::Result<()> {
+        let thing = thing.into();
+        if self.leaf.count >= self.leaf_capacity {
+            self.spill()?;
+        }
+        match self.leaf.add(thing.clone()) {
+            Ok(()) => {}
+            Err(ring::Error::Limit(nix::Error::Sys(nix::Errno::EAGAIN))) if self.leaf.count > 0 => {
+                self.spill()?;
+                self.leaf.add(thing)?;
+            }
+            Err(e) => { return Err(e); }
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    /// (internal) Stashes the current leaf in the parent rings and
+    /// starts a new leaf.
+    fn spill(&mut self) -> ring::Result<()> {
+        if self.max_depth == 0 {
+            return Err(full());
+        }
+        if self.spilled.is_none() {
+            let parent = with_leaf_capacity(self.fan_out, self.fan_out, self.max_depth - 1)?;
+            self.spilled = Some(Box::new(parent));
+        }
+        let fresh = ring::new()?;
+        let leaf = mem::replace(&mut self.leaf, fresh);
+        let stashed = match self.spilled {
+            Some(ref mut spilled) => spilled.add(&leaf),
+            None => unreachable!(),
+        };
+        if let Err(e) = stashed {
+            // Keep the full leaf, so nothing is lost:
+            self.leaf = leaf;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Removes and returns the oldest entry in the tree.
+    pub fn pop(&mut self) -> ring::Result<StashedThing> {
+        let thing = self.remove()?;
+        self.count -= 1;
+        Ok(thing)
+    }
+
+    /// (internal) Removes the oldest entry: from the full leaf we're
+    /// popping from, else from the oldest full leaf, else from the
+    /// current leaf.
+    fn remove(&mut self) -> ring::Result<StashedThing> {
+        if let Some(ref mut head) = self.head {
+            if head.count > 0 {
+                return head.pop();
+            }
+        }
+        self.head = None;
+
+        let mut spilled_empty = true;
+        if let Some(ref mut spilled) = self.spilled {
+            if spilled.count > 0 {
+                spilled_empty = false;
+                match spilled.pop()? {
+                    StashedThing::Pair(ring) => { self.head = Some(ring); }
+                    _ => { return Err(ring::Error::Protocol(ring::ProtocolError::RingFormatError)); }
+                }
+            }
+        }
+        if spilled_empty {
+            return self.leaf.pop();
+        }
+        self.remove()
+    }
+}
+
+// Unit tests follow:
+
+#[cfg(target_os="linux")]
+#[test]
+fn tree_spills_and_pops_in_order() {
+    use std::ffi::CString;
+    use kind;
+
+    let _leaks = ::leaks::guard();
+    let mut tree = with_leaf_capacity(3, 2, 4).unwrap();
+    for i in 0..20 {
+        let name = CString::new(format!("{}", i)).unwrap();
+        let fd = nix::sys::memfd::memfd_create(name.as_ref(), nix::sys::memfd::MemFdCreateFlag::empty()).unwrap();
+        tree.add(fd).unwrap();
+        nix::unistd::close(fd).unwrap();
+    }
+    assert_eq!(20, tree.count);
+    assert_eq!(2, tree.depth());
+
+    for i in 0..20 {
+        match tree.pop().unwrap() {
+            StashedThing::One(fd) => {
+                assert_eq!(kind::Description::MemFd(format!("{}", i)), kind::describe(fd).unwrap());
+                nix::unistd::close(fd).unwrap();
+            }
+            _ => { panic!("Expected a single FD"); }
+        }
+    }
+    assert_eq!(0, tree.count);
+}
+
+#[test]
+fn tree_respects_max_depth() {
+    let _leaks = ::leaks::guard();
+    let mut tree = with_leaf_capacity(2, 2, 1).unwrap();
+    let (one, two) = super::unix_socket_pair().unwrap();
+    for _ in 0..6 {
+        tree.add(one).unwrap();
+    }
+    match tree.add(one) {
+        Err(ring::Error::Limit(nix::Error::Sys(nix::Errno::EAGAIN))) => {}
+        _ => { panic!("The tree should be full"); }
+    }
+    assert_eq!(6, tree.count);
+    while tree.count > 0 {
+        match tree.pop().unwrap() {
+            StashedThing::One(fd) => { nix::unistd::close(fd).unwrap(); }
+            _ => { panic!("Expected a single FD"); }
+        }
+    }
+    nix::unistd::close(one).unwrap();
+    nix::unistd::close(two).unwrap();
+}
@@ -0,0 +1,185 @@
+//! A safety guard that stops experiments before the machine they run
+//! on becomes unusable.
+//!
+//! Stashing FDs until the kernel runs out of file handles means that
+//! every other process on the host runs out of them too. If you
+//! [`arm`](fn.arm.html) a [`Watchdog`](struct.Watchdog.html), the
+//! helpers that make throwaway FDs (like
+//! [`add_tmpfile_to_ring`](../fn.add_tmpfile_to_ring.html)) stop with
+//! a [`Tripped`](../ring/enum.Error.html#variant.Tripped) error once
+//! the system is low on free file handles or the time budget is used
+//! up. Dropping the rings you stashed things in then frees everything
+//! again.
+//!
+//! The watchdog is armed per thread, so tests running in parallel
+//! don't see each other's watchdogs.
+
+use std::cell::RefCell;
+use std::io;
+use std::time::{Duration, Instant};
+
+use libc;
+use monitor::FileTable;
+
+/// How often (in calls to [`check`](fn.check.html)) to look at the
+/// system's file table, by default.
+pub const DEFAULT_CHECK_EVERY: u64 = 64;
+
+/// The reason a watchdog stopped an experiment.
+#[derive(Copy, PartialEq, Eq, Clone, Debug)]
+pub enum Tripped {
+    /// The system had only this many free file handles left
+    LowOnFiles(u64),
+
+    /// The experiment has run for longer than its budget
+    OutOfTime(Duration),
+}
+
+/// The limits an experiment has to stay within.
+#[derive(Copy, PartialEq, Eq, Clone, Debug)]
+pub struct Watchdog {
+    /// Trip once fewer than this many file handles are free system-wide
+    pub min_free: u64,
+
+    /// Trip once this much time has passed since arming
+    pub budget: Option<Duration>,
+
+    /// Look at the system's file table only every this many checks
+    /// (reading it takes a few syscalls, and an FD!); 0 counts as 1
+    pub check_every: u64,
+}
+
+impl Watchdog {
+    /// Creates a watchdog that trips once fewer than `min_free` file
+    /// handles are free, or once `budget` has passed.
+    pub fn new(min_free: u64, budget: Option<Duration>) -> Watchdog {
+        Watchdog {
+            min_free: min_free,
+            budget: budget,
+            check_every: DEFAULT_CHECK_EVERY,
+        }
+    }
+
+    /// Creates a watchdog that keeps `percent`% of the system's file
+    /// handles free.
+    pub fn keeping_free(percent: u64, budget: Option<Duration>) -> io::Result<Watchdog> {
+        let table = FileTable::current()?;
+        Ok(Watchdog::new(table.max / 100 * percent, budget))
+    }
+}
+
+struct State {
+    watchdog: Watchdog,
+    armed_at: Instant,
+    checks: u64,
+}
+
+thread_local!(static ARMED: RefCell<Option<State>> = RefCell::new(None));
+
+/// Disarms the watchdog when dropped.
+pub struct Armed {
+    _private: (),
+}
+
+impl Drop for Armed {
+    fn drop(&mut self) {
+        ARMED.with(|armed| *armed.borrow_mut() = None);
+    }
+}
+
+/// Arms `watchdog` for the current thread, replacing any watchdog
+/// that was armed before. It stays armed until the returned value is
+/// dropped.
+pub fn arm(mut watchdog: Watchdog) -> Armed {
+    if watchdog.check_every == 0 {
+        watchdog.check_every = 1;
+    }
+    ARMED.with(|armed| {
+        *armed.borrow_mut() = Some(State {
+            watchdog: watchdog,
+            armed_at: Instant::now(),
+            checks: 0,
+        });
+    });
+    Armed { _private: () }
+}
+
+/// Returns an error if the current thread's watchdog has tripped.
+/// Without an armed watchdog, this always succeeds.
+pub fn check() -> Result<(), Tripped> {
+    ARMED.with(|armed| {
+        let mut armed = armed.borrow_mut();
+        let state = match *armed {
+            Some(ref mut state) => state,
+            None => return Ok(()),
+        };
+        if let Some(budget) = state.watchdog.budget {
+            let elapsed = state.armed_at.elapsed();
+            if elapsed >= budget {
+                return Err(Tripped::OutOfTime(elapsed));
+            }
+        }
+
+        let checks = state.checks;
+        state.checks += 1;
+        if checks % state.watchdog.check_every != 0 {
+            return Ok(());
+        }
+        match FileTable::current() {
+            Ok(table) => {
+                if table.free() < state.watchdog.min_free {
+                    return Err(Tripped::LowOnFiles(table.free()));
+                }
+                Ok(())
+            }
+            // We couldn't open fs.file-nr because the system is out
+            // of file handles entirely:
+            Err(ref e) if e.raw_os_error() == Some(libc::ENFILE) => {
+                Err(Tripped::LowOnFiles(0))
+            }
+            // Other errors (e.g. EMFILE, no /proc) say nothing about
+            // the system, so carry on:
+            Err(_) => Ok(()),
+        }
+    })
+}
+
+// Unit tests follow:
+
+#[test]
+fn it_trips_when_out_of_time() {
+    let _armed = arm(Watchdog::new(0, Some(Duration::from_secs(0))));
+    match check() {
+        Err(Tripped::OutOfTime(_)) => {}
+        other => { panic!("Expected to run out of time, got {:?}", other); }
+    }
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn it_trips_when_low_on_files() {
+    let _leaks = ::leaks::guard();
+    {
+        let _armed = arm(Watchdog::new(u64::max_value(), None));
+        match check() {
+            Err(Tripped::LowOnFiles(_)) => {}
+            other => { panic!("Expected to be low on files, got {:?}", other); }
+        }
+    }
+    assert_eq!(Ok(()), check());
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn checking_every_0_checks_every_time() {
+    let _leaks = ::leaks::guard();
+    let mut watchdog = Watchdog::new(u64::max_value(), None);
+    watchdog.check_every = 0;
+    let _armed = arm(watchdog);
+    for _ in 0..2 {
+        match check() {
+            Err(Tripped::LowOnFiles(_)) => {}
+            other => { panic!("Expected to be low on files, got {:?}", other); }
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! What ring entries look like as messages.
+//!
+//! Every entry is one message: a short payload plus the FDs that go
+//! with it, passed as `SCM_RIGHTS`.
+//!
+//! * `!` with one FD is a single FD.
+//! * `*` with 1 to [`MAX_FDS_PER_ENTRY`](../ring/constant.MAX_FDS_PER_ENTRY.html)
+//!   FDs is a group.
+//! * A decimal number with two FDs is a ring: its read end, its write
+//!   end, and how many entries it holds.
+//!
+//! Whoever holds the write end of a ring can send anything down it,
+//! though. [`decode`](fn.decode.html) doesn't touch the FDs or make
+//! any syscalls, so it can be tested (and fuzzed) with made-up
+//! messages; anything it doesn't understand comes back as a
+//! [`Malformed`](struct.Malformed.html) message, together with every
+//! FD that came with it, so the caller can close them.
+
+use nix::sys::socket;
+use std::os::unix::io::RawFd;
+use std::result;
+use std::str;
+use std::str::FromStr;
+
+use ring::{ProtocolError, MAX_FDS_PER_ENTRY};
+
+/// Payload that marks an entry as a single FD.
+pub const ONE_MARKER: &'static [u8] = b"!";
+
+/// Payload that marks an entry as a group of FDs.
+pub const GROUP_MARKER: &'static [u8] = b"*";
+
+/// A decoded ring entry. It owns the FDs in it.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Entry {
+    One(RawFd),
+    Group(Vec<RawFd>),
+    Ring { read: RawFd, write: RawFd, count: u64 },
+}
+
+impl Entry {
+    /// All the FDs in the entry.
+    pub fn fds(&self) -> Vec<RawFd> {
+        match *self {
+            Entry::One(fd) => vec![fd],
+            Entry::Group(ref fds) => fds.clone(),
+            Entry::Ring { read, write, .. } => vec![read, write],
+        }
+    }
+}
+
+/// A message that isn't a valid entry.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Malformed {
+    /// What's wrong with it
+    pub error: ProtocolError,
+
+    /// The FDs that came with it, which need closing
+    pub fds: Vec<RawFd>,
+}
+
+/// Decodes a message received from a ring: `payload` is the data
+/// that was received, `fds` the FDs that came with it, and `flags`
+/// the flags `recvmsg` returned.
+///
+/// Messages that were truncated (`MSG_TRUNC` or `MSG_CTRUNC`) are
+/// always malformed; the kernel has dropped some of what was sent.
+pub fn decode(payload: &[u8], fds: Vec<RawFd>, flags: socket::MsgFlags) -> result::Result<Entry, Malformed> {
+    let malformed = |error, fds| Err(Malformed { error: error, fds: fds });
+
+    if flags.intersects(socket::MSG_TRUNC | socket::MSG_CTRUNC) {
+        return malformed(ProtocolError::Truncated, fds);
+    }
+    if fds.is_empty() {
+        return malformed(ProtocolError::NoFDReceived(2), fds);
+    }
+    if payload == GROUP_MARKER {
+        if fds.len() > MAX_FDS_PER_ENTRY {
+            return malformed(ProtocolError::TooManyFDsReceived, fds);
+        }
+        return Ok(Entry::Group(fds));
+    }
+    match fds.len() {
+        1 if payload == ONE_MARKER => Ok(Entry::One(fds[0])),
+        1 => malformed(ProtocolError::RingFormatError, fds),
+        2 => {
+            match parse_count(payload) {
+                Some(count) => Ok(Entry::Ring { read: fds[0], write: fds[1], count: count }),
+                None => malformed(ProtocolError::RingFormatError, fds),
+            }
+        }
+        _ => malformed(ProtocolError::TooManyFDsReceived, fds),
+    }
+}
+
+// Only plain decimal digits; `u64::from_str` would take a leading `+`.
+fn parse_count(payload: &[u8]) -> Option<u64> {
+    if payload.is_empty() || !payload.iter().all(|b| b'0' <= *b && *b <= b'9') {
+        return None;
+    }
+    str::from_utf8(payload).ok().and_then(|s| u64::from_str(s).ok())
+}
+
+// Unit tests follow:
+
+#[test]
+fn valid_entries_decode() {
+    let none = socket::MsgFlags::empty();
+    assert_eq!(Ok(Entry::One(3)), decode(b"!", vec![3], none));
+    assert_eq!(Ok(Entry::Group(vec![3])), decode(b"*", vec![3], none));
+    assert_eq!(Ok(Entry::Group(vec![3, 4, 5])), decode(b"*", vec![3, 4, 5], none));
+    assert_eq!(Ok(Entry::Ring { read: 3, write: 4, count: 0 }), decode(b"0", vec![3, 4], none));
+    assert_eq!(Ok(Entry::Ring { read: 3, write: 4, count: 12345 }), decode(b"12345", vec![3, 4], none));
+}
+
+#[test]
+fn malformed_entries_give_back_their_fds() {
+    let none = socket::MsgFlags::empty();
+    let cases: Vec<(&[u8], Vec<RawFd>, socket::MsgFlags, ProtocolError)> = vec![
+        (b"!", vec![], none, ProtocolError::NoFDReceived(2)),
+        (b"*", vec![], none, ProtocolError::NoFDReceived(2)),
+        (b"", vec![3], none, ProtocolError::RingFormatError),
+        (b"7", vec![3], none, ProtocolError::RingFormatError),
+        (b"!", vec![3, 4], none, ProtocolError::RingFormatError),
+        (b"", vec![3, 4], none, ProtocolError::RingFormatError),
+        (b"+1", vec![3, 4], none, ProtocolError::RingFormatError),
+        (b"-1", vec![3, 4], none, ProtocolError::RingFormatError),
+        (b"99999999999999999999999", vec![3, 4], none, ProtocolError::RingFormatError),
+        (&[0xff, 0xfe], vec![3, 4], none, ProtocolError::RingFormatError),
+        (b"!", vec![3, 4, 5], none, ProtocolError::TooManyFDsReceived),
+        (b"*", (0..16).collect(), none, ProtocolError::TooManyFDsReceived),
+        (b"!", vec![3], socket::MSG_TRUNC, ProtocolError::Truncated),
+        (b"*", vec![3, 4], socket::MSG_CTRUNC, ProtocolError::Truncated),
+    ];
+    for (payload, fds, flags, error) in cases {
+        let expected = Err(Malformed { error: error, fds: fds.clone() });
+        assert_eq!(expected, decode(payload, fds, flags), "decoding {:?}", payload);
+    }
+}
+
+// Decodes a few thousand random messages, making sure that every FD
+// ends up either in the entry or in the error (like the fuzz target
+// in `fuzz/`, only deterministic).
+#[test]
+fn random_messages_never_lose_fds() {
+    let mut state: u32 = 4242;
+    let mut next = || {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        (state >> 16) as usize
+    };
+    let alphabet = b"!*0123456789+- \xff";
+    for _ in 0..5000 {
+        let payload: Vec<u8> = (0..next() % 6).map(|_| alphabet[next() % alphabet.len()]).collect();
+        let fds: Vec<RawFd> = (0..next() % 18).map(|i| i as RawFd + 100).collect();
+        let flags = socket::MsgFlags::from_bits_truncate(next() as i32);
+        let mut got = match decode(&payload, fds.clone(), flags) {
+            Ok(entry) => entry.fds(),
+            Err(malformed) => malformed.fds,
+        };
+        got.sort();
+        assert_eq!(fds, got, "decoding {:?} with {:?}", payload, flags);
+    }
+}
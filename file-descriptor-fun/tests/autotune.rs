@@ -0,0 +1,34 @@
+extern crate filedes;
+extern crate nix;
+
+use filedes::{autotune, leaks};
+use filedes::sandbox::{Limits, SAFE_NOFILE};
+use nix::sys::socket::SockType;
+
+// A few trials are enough to see the sweep works; the full default
+// sweep takes a while.
+#[cfg(not(target_os="macos"))]
+#[test]
+fn a_small_sweep_finds_a_best_config() {
+    let _leaks = leaks::guard();
+    let sweep = autotune::Sweep {
+        send_buf_sizes: vec![16 * 1024],
+        fds_per_message: vec![1, 4],
+        socket_types: vec![SockType::Stream, SockType::Datagram],
+        fan_outs: vec![0, 4],
+        max_stashed: 500,
+        limits: Limits::nofile(SAFE_NOFILE),
+    };
+    assert_eq!(8, sweep.configs().len());
+
+    let results = sweep.run().unwrap();
+    println!("{}", results);
+    assert_eq!(8, results.trials.len());
+    for trial in results.trials.iter() {
+        assert_eq!(None, trial.error);
+        assert!(trial.stashed <= sweep.max_stashed);
+    }
+    let best = results.best_per_slot().unwrap();
+    assert!(best.stashed > 0);
+    assert!(best.per_slot() > 0.0);
+}
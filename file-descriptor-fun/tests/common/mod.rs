@@ -0,0 +1,42 @@
+//! Helpers shared by the integration tests.
+
+use filedes::fdtable;
+use filedes::fdtable::FdEntry;
+use std::os::unix::io::RawFd;
+
+/// Takes the FD table that `check_fd_table` compares against.
+#[cfg(target_os="linux")]
+pub fn fd_table() -> Vec<FdEntry> {
+    fdtable::entries().unwrap()
+}
+
+#[cfg(not(target_os="linux"))]
+pub fn fd_table() -> Vec<FdEntry> {
+    vec![]
+}
+
+/// Prints the FD table and checks that, compared to `before`, the
+/// FDs in `expected` are the only ones that were opened, and none
+/// were closed. Whatever backend the throwaway FDs came from, they
+/// should all be on rings by now.
+#[cfg(target_os="linux")]
+pub fn check_fd_table(before: &[FdEntry], expected: &[RawFd]) {
+    let table = fdtable::entries().unwrap();
+    for entry in table.iter() {
+        println!("{}", entry);
+    }
+    let changes = fdtable::changes(before, &table);
+    for entry in changes.closed.iter() {
+        println!("Closed since the test started: {}", entry);
+    }
+    for entry in changes.opened.iter().filter(|e| !expected.contains(&e.fd)) {
+        println!("Still open outside the ring: {}", entry);
+    }
+    let mut expected = expected.to_vec();
+    expected.sort();
+    assert_eq!(expected, changes.opened.iter().map(|e| e.fd).collect::<Vec<_>>());
+    assert!(changes.closed.is_empty());
+}
+
+#[cfg(not(target_os="linux"))]
+pub fn check_fd_table(_before: &[FdEntry], _expected: &[RawFd]) {}
@@ -2,16 +2,17 @@ extern crate filedes;
 extern crate nix;
 
 use std::thread;
+use filedes::{leaks, ring};
+use filedes::kind::Description;
 use nix::sys::socket;
 use nix::sys::uio::IoVec;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use nix::unistd;
 
-#[test]
-fn it_sends_fds() {
-    filedes::setup().unwrap();
-    let s_sock = filedes::server_socket("mysock3").unwrap();
-    thread::spawn(move || {
+// Passes an FD from a server thread listening on `addr` to a client.
+fn send_an_fd_via(addr: &filedes::SocketAddress) {
+    let s_sock = filedes::server_socket_at(addr).unwrap();
+    let server = thread::spawn(move || {
         let conn = socket::accept(s_sock).unwrap();
 
         let buf = vec![IoVec::from_slice("!".as_bytes())];
@@ -24,18 +25,69 @@ fn it_sends_fds() {
                         socket::MsgFlags::empty(),
                         None)
             .unwrap();
+        unistd::close(conn).unwrap();
         unistd::close(s_sock).unwrap();
     });
-    let sock = filedes::connect_to_socket("mysock3").unwrap();
+    let sock = filedes::connect_to_socket_at(addr).unwrap();
 
     let mut backing_buf = vec![0];
     let mut buf = vec![IoVec::from_mut_slice(&mut backing_buf)];
-    let mut cmsg: socket::CmsgSpace<([RawFd; 15])> = socket::CmsgSpace::new();
+    let mut cmsg: socket::CmsgSpace<[RawFd; 15]> = socket::CmsgSpace::new();
     let msg = socket::recvmsg(sock,
                               &mut buf.as_mut_slice(),
                               Some(&mut cmsg),
                               socket::MsgFlags::empty())
         .unwrap();
     assert_eq!(1, msg.cmsgs().count());
-    filedes::teardown().unwrap();
+    for cmsg in msg.cmsgs() {
+        if let socket::ControlMessage::ScmRights(fds) = cmsg {
+            for &fd in fds {
+                unistd::close(fd).unwrap();
+            }
+        }
+    }
+    unistd::close(sock).unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn it_sends_fds() {
+    let _leaks = leaks::guard();
+    let dir = filedes::SocketDir::new().unwrap();
+    send_an_fd_via(&dir.address("mysock3"));
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn it_sends_fds_over_abstract_sockets() {
+    let _leaks = leaks::guard();
+    send_an_fd_via(&filedes::SocketAddress::unique_abstract());
+}
+
+fn stash_one_and_describe() -> filedes::kind::Description {
+    let mut ring = ring::new().unwrap();
+    assert_eq!(1, filedes::add_tmpfile_to_ring(&mut ring).unwrap());
+    let fd = ring.pop().unwrap().into_file().unwrap();
+    filedes::kind::describe(fd.as_raw_fd()).unwrap()
+}
+
+// Both backends should work on Linux, no matter which one is the
+// default (see `make test-mkstemp`):
+#[cfg(target_os="linux")]
+#[test]
+fn both_throwaway_backends_work() {
+    let _leaks = leaks::guard();
+    filedes::set_backend(filedes::Backend::MemFd);
+    match stash_one_and_describe() {
+        Description::MemFd(_) => {}
+        other => { panic!("Expected a memfd, got {}", other); }
+    }
+
+    filedes::set_backend(filedes::Backend::TempFile);
+    match stash_one_and_describe() {
+        Description::File(path) => {
+            assert!(path.to_string_lossy().starts_with("/tmp/filedes_fun."), "Unexpected path {:?}", path);
+        }
+        other => { panic!("Expected a temp file, got {}", other); }
+    }
 }
@@ -0,0 +1,50 @@
+extern crate filedes;
+extern crate nix;
+
+use filedes::{leaks, nesting, ring, watchdog};
+use std::time::Duration;
+
+/// Way more than older kernels let you nest (newer ones may not have
+/// a limit at all).
+const MAX_DEPTH: u64 = 100;
+
+// The kernel's limit on nesting sockets in sockets is a constant, so
+// we should get the same depth every time.
+//
+// On OS X, stashing rings doesn't work at all (see ring_in_ring.rs).
+#[cfg(not(target_os="macos"))]
+#[test]
+fn nesting_depth_is_stable() {
+    let _leaks = leaks::guard();
+    let first = nesting::probe(MAX_DEPTH).unwrap();
+    println!("First probe: {}", first);
+    assert!(first.depth >= 1);
+    match first.stopped_by {
+        None => {
+            println!("The probe stopped at the cap of {}, the kernel didn't stop it", MAX_DEPTH);
+            assert_eq!(MAX_DEPTH, first.depth);
+        }
+        Some(nix::Error::Sys(nix::Errno::ETOOMANYREFS)) => {
+            println!("The kernel's nesting limit stopped the probe");
+            assert!(first.depth < MAX_DEPTH);
+        }
+        Some(e) => {
+            println!("Another limit ({}) stopped the probe before the kernel or the cap did", e);
+            assert!(first.depth < MAX_DEPTH);
+        }
+    }
+
+    for _ in 0..3 {
+        assert_eq!(first, nesting::probe(MAX_DEPTH).unwrap());
+    }
+}
+
+#[test]
+fn a_tripped_watchdog_stops_the_probe() {
+    let _leaks = leaks::guard();
+    let _armed = watchdog::arm(watchdog::Watchdog::new(0, Some(Duration::from_secs(0))));
+    match nesting::probe(MAX_DEPTH) {
+        Err(ring::Error::Tripped(watchdog::Tripped::OutOfTime(_))) => {}
+        other => { panic!("Expected the watchdog to trip, got {:?}", other); }
+    }
+}
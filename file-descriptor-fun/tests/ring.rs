@@ -1,13 +1,16 @@
 extern crate filedes;
 extern crate nix;
 
-use filedes::ring;
-use filedes::{add_two_sockets_to_ring,add_tmpfile_to_ring};
+mod common;
+
+use filedes::{ring, leaks, limits};
+use filedes::add_tmpfile_to_ring;
 use std::os::unix::io::RawFd;
-use std::process::Command;
 
 #[test]
 fn adding_many_to_a_ring_works() {
+    let _leaks = leaks::guard();
+    let before = common::fd_table();
     let mut ring = ring::new().unwrap();
 
     loop {
@@ -36,6 +39,16 @@ fn adding_many_to_a_ring_works() {
     }
     println!("I managed to store a bunch of FDs in {}", ring);
     println!("...and I opened {} FDs", additional_fds.len());
+    let fd_limits = limits::FdLimits::current().unwrap();
+    let held = ring.count + additional_fds.len() as u64;
+    match fd_limits.exceeded_by(held) {
+        Some((limit, by)) => {
+            println!("Holding {} FDs, that's {} past the {:?} limit ({})", held, by, limit, fd_limits);
+        }
+        None => {
+            println!("Holding {} FDs, within the limits ({})", held, fd_limits);
+        }
+    }
     assert!(additional_fds.len() > 0);
 
     // println!("Waiting 60s");
@@ -45,12 +58,9 @@ fn adding_many_to_a_ring_works() {
     for fd in additional_fds {
         nix::unistd::close(fd).unwrap();
     }
-    println!("I still have {} FDs open, but let's see! lsof output follows:", ring.count);
-    let output = Command::new("lsof")
-        .arg("-p")
-        .arg(format!("{}", nix::unistd::getpid()))
-        .output().unwrap();
-    println!("{}", String::from_utf8(output.stdout).unwrap());
+    println!("I still have {} FDs open, but let's see! FD table follows:", ring.count);
+    let (read, write) = ring.raw_fds();
+    common::check_fd_table(&before, &[read, write]);
 
     let should_close = ring.count;
     let mut closed = 0;
@@ -62,6 +72,11 @@ fn adding_many_to_a_ring_works() {
                 nix::unistd::close(fd).unwrap();
             }
             ring::StashedThing::Pair(_) => {}
+            ring::StashedThing::Group(fds) => {
+                for fd in fds {
+                    nix::unistd::close(fd).unwrap();
+                }
+            }
         }
     }
     assert_eq!(should_close, closed);
@@ -75,6 +90,11 @@ fn adding_many_to_a_ring_works() {
             }
             ring::StashedThing::Pair(_) => {
             }
+            ring::StashedThing::Group(fds) => {
+                for fd in fds {
+                    nix::unistd::close(fd).unwrap();
+                }
+            }
         }
     }
 }
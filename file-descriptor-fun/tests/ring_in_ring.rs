@@ -1,9 +1,11 @@
 extern crate filedes;
 extern crate nix;
 
-use filedes::ring;
-use filedes::{add_two_sockets_to_ring,add_tmpfile_to_ring};
-use std::process::Command;
+mod common;
+
+use filedes::{ring, leaks, monitor, watchdog};
+use std::time::Duration;
+use filedes::add_tmpfile_to_ring;
 use std::io;
 use std::io::Write;
 
@@ -13,6 +15,12 @@ use std::io::Write;
 /// down to, say, 50.
 const ARBITRARY_LIMIT: u64 = 400;
 
+/// Stop before the rest of the machine is out of file handles, too:
+/// keep this percentage of them free, and don't run for longer than
+/// this many seconds.
+const KEEP_FREE_PERCENT: u64 = 10;
+const TIME_BUDGET_SECS: u64 = 120;
+
 // In Linux, this works! We can send rings down rings, and the system
 // will get very very slow, but sockets containing FDs can be sent
 // down sockets, and can be read off them.
@@ -22,12 +30,23 @@ const ARBITRARY_LIMIT: u64 = 400;
 #[cfg(not(target_os="macos"))]
 #[test]
 fn adding_rings_to_rings_works() {
+    let _leaks = leaks::guard();
+    let before = common::fd_table();
     let mut outer_ring = ring::new().unwrap();
     let mut total = 0;
     let mut outer_entries = 0;
+    // Only works where there's a /proc/sys:
+    let mut file_table = monitor::Monitor::new(25).ok();
+    let budget = Some(Duration::from_secs(TIME_BUDGET_SECS));
+    let _armed = watchdog::arm(watchdog::Watchdog::keeping_free(KEEP_FREE_PERCENT, budget)
+                               .unwrap_or(watchdog::Watchdog::new(0, budget)));
     println!("One dot corresponds to one entry on the outer ring:");
 
     'outer: loop {
+        if let Err(reason) = watchdog::check() {
+            println!("\nThe watchdog stopped us: {:?}", reason);
+            break 'outer;
+        }
         let mut inner_ring = ring::new().unwrap();
         'inner: loop {
             match add_tmpfile_to_ring(&mut inner_ring) {
@@ -54,20 +73,29 @@ fn adding_rings_to_rings_works() {
                     total -= inner_ring.count;
                     break 'outer;
                 }
+                Err(ring::Error::Tripped(reason)) => {
+                    println!("\nThe watchdog stopped us: {:?}", reason);
+                    total -= inner_ring.count;
+                    break 'outer;
+                }
                 e => { panic!("\nError {:?}", e); }
             }
         }
         outer_entries += 1;
+        if let Some(ref mut file_table) = file_table {
+            file_table.step().unwrap();
+        }
         if outer_entries > ARBITRARY_LIMIT {
             break;
         }
     }
-    println!("Assembled an outer ring of {} for a total of {} FDs, lsof output follows:", outer_ring, total);
-    let output = Command::new("lsof")
-        .arg("-p")
-        .arg(format!("{}", nix::unistd::getpid()))
-        .output().unwrap();
-    println!("{}", String::from_utf8(output.stdout).unwrap());
+    println!("Assembled an outer ring of {} for a total of {} FDs, FD table follows:", outer_ring, total);
+    let (read, write) = outer_ring.raw_fds();
+    common::check_fd_table(&before, &[read, write]);
+    if let Some(mut file_table) = file_table {
+        file_table.sample().unwrap();
+        println!("{}", file_table);
+    }
     assert!(outer_ring.count > 1);
 
     println!("Now I'm going to close all these one by one, hang tight.");
@@ -78,8 +106,8 @@ fn adding_rings_to_rings_works() {
                 while inner_ring.count > 0 {
                     let thing = inner_ring.pop().unwrap();
                     match thing {
-                        ring::StashedThing::Pair(_) => {
-                            panic!("I don't know how I could get to a ring in inner");
+                        ring::StashedThing::Pair(_) | ring::StashedThing::Group(_) => {
+                            panic!("I don't know how I could get to a ring or group in inner");
                         }
                         ring::StashedThing::One(fd) => {
                             nix::unistd::close(fd).unwrap();
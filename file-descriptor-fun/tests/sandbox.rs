@@ -0,0 +1,106 @@
+extern crate filedes;
+extern crate nix;
+
+use filedes::{inflight, leaks, ring, sandbox, scenario};
+use filedes::sandbox::{Limits, Outcome, Report, SAFE_NOFILE};
+
+fn completed(outcome: Outcome) -> Report {
+    match outcome {
+        Outcome::Completed(report) => report,
+        Outcome::Died(status) => { panic!("The child died: {:?}", status); }
+    }
+}
+
+#[test]
+fn filling_a_ring_is_repeatable() {
+    let _leaks = leaks::guard();
+    let limits = Limits::nofile(SAFE_NOFILE);
+    let first = completed(sandbox::run(&limits, scenario::fill_ring).unwrap());
+    let second = completed(sandbox::run(&limits, scenario::fill_ring).unwrap());
+    println!("First run: {:?}, second run: {:?}", first, second);
+    assert_eq!(None, first.error);
+    assert!(first.get("stashed").unwrap() > 0);
+    assert_eq!(first, second);
+}
+
+#[cfg(not(target_os="macos"))]
+#[test]
+fn nesting_rings_in_a_sandbox_works() {
+    let _leaks = leaks::guard();
+    let limits = Limits::nofile(SAFE_NOFILE);
+    let report = completed(sandbox::run(&limits, |r| scenario::nest_rings(5, r)).unwrap());
+    println!("{:?}", report);
+    assert_eq!(None, report.error);
+    if inflight::kernel_caps_in_flight().unwrap() {
+        // The first inner ring fills up to the in-flight limit (see
+        // below), so there's no room left to stash it:
+        assert_eq!(Some(nix::Errno::ETOOMANYREFS as i32 as u64), report.get("errno"));
+        assert_eq!(Some(0), report.get("outer_entries"));
+        assert_eq!(Some(0), report.get("stashed"));
+    } else {
+        assert_eq!(None, report.get("errno"));
+        assert_eq!(Some(5), report.get("outer_entries"));
+        assert!(report.get("stashed").unwrap() > 0);
+    }
+}
+
+#[test]
+fn the_child_really_has_lower_limits() {
+    let _leaks = leaks::guard();
+    let limits = Limits::nofile(SAFE_NOFILE);
+    let report = completed(sandbox::run(&limits, |report| {
+        let mut fds = vec![];
+        loop {
+            match filedes::unix_socket_pair() {
+                Ok((one, two)) => { fds.push(one); fds.push(two); }
+                Err(e) => {
+                    report.set_errno(&e);
+                    break;
+                }
+            }
+        }
+        report.set("opened", fds.len() as u64);
+        Ok(())
+    }).unwrap());
+    assert!(report.get("opened").unwrap() < SAFE_NOFILE);
+    assert_eq!(Some(nix::Errno::EMFILE as i32 as u64), report.get("errno"));
+}
+
+#[test]
+fn errors_and_panics_are_reported() {
+    let _leaks = leaks::guard();
+    let limits = Limits::default();
+    let report = completed(sandbox::run(&limits, |_| {
+        Err(ring::Error::Protocol(ring::ProtocolError::RingFormatError))
+    }).unwrap());
+    assert!(report.error.is_some());
+
+    match sandbox::run(&limits, |_| { panic!("on purpose") }).unwrap() {
+        Outcome::Died(_) => {}
+        Outcome::Completed(report) => { panic!("Expected the child to die, got {:?}", report); }
+    }
+}
+
+// Since Linux 4.5, FDs in flight count against RLIMIT_NOFILE unless
+// we're privileged; before that (or with CAP_SYS_RESOURCE), we can
+// get way more in flight than we could have open.
+#[cfg(target_os="linux")]
+#[test]
+fn in_flight_fds_are_accounted_for() {
+    let _leaks = leaks::guard();
+    let limits = Limits::nofile(SAFE_NOFILE);
+    let report = completed(sandbox::run(&limits, |r| scenario::in_flight(8, r)).unwrap());
+    println!("{:?}", report);
+    assert_eq!(None, report.error);
+    assert_eq!(Some(SAFE_NOFILE), report.get("nofile_soft"));
+    let in_flight = report.get("in_flight").unwrap();
+    if inflight::kernel_caps_in_flight().unwrap() {
+        println!("The kernel capped us at {} FDs in flight", in_flight);
+        assert_eq!(Some(nix::Errno::ETOOMANYREFS as i32 as u64), report.get("errno"));
+        assert!(in_flight <= SAFE_NOFILE + 1);
+    } else {
+        println!("Got {} FDs in flight, the kernel didn't stop us", in_flight);
+        assert_eq!(None, report.get("errno"));
+        assert!(in_flight > SAFE_NOFILE);
+    }
+}